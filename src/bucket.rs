@@ -1,17 +1,36 @@
 use crate::constants::BUCKET_SIZE;
 use crate::contact::Contact;
 use crate::kademlia_id::KademliaID;
+use crate::limits::DiversityLimits;
 use std::collections::LinkedList;
 
 #[derive(Clone)]
 pub struct Bucket {
     list: LinkedList<Contact>,
+    size: usize,
+    limits: DiversityLimits,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Bucket {
     pub fn new() -> Self {
+        Self::with_size(BUCKET_SIZE)
+    }
+
+    pub fn with_size(size: usize) -> Self {
+        Self::with_limits(size, DiversityLimits::default())
+    }
+
+    pub fn with_limits(size: usize, limits: DiversityLimits) -> Self {
         Self {
             list: LinkedList::<Contact>::new(),
+            size,
+            limits,
         }
     }
     pub fn add_contact(&mut self, contact: &Contact, target: KademliaID) -> &Self {
@@ -20,15 +39,23 @@ impl Bucket {
         if self.list.iter().any(|c| c.id == contact.id) {
             return self;
         }
-        if self.list.len() < BUCKET_SIZE {
+        if !self.limits.admits(self.list.iter(), contact) {
+            println!(
+                "Bucket already has enough contacts from {}, not adding {}",
+                contact.address,
+                contact.id.to_hex()
+            );
+            return self;
+        }
+        if self.list.len() < self.size {
             self.list.push_back(contact_clone);
         } else {
             let mut contacts: Vec<Contact> = self.list.iter().cloned().collect();
             contacts.push(contact_clone);
 
-            contacts.sort_by(|a, b| a.get_distance().cmp(&b.get_distance()));
+            contacts.sort_by_key(|a| a.get_distance());
 
-            contacts.truncate(BUCKET_SIZE);
+            contacts.truncate(self.size);
 
             self.list = contacts.into_iter().collect();
         }
@@ -36,6 +63,11 @@ impl Bucket {
         self
     }
 
+    pub fn remove_contact(&mut self, id: KademliaID) -> &Self {
+        self.list = self.list.iter().filter(|c| c.id != id).cloned().collect();
+        self
+    }
+
     pub fn get_contact_and_calc_distance(&mut self, target: KademliaID) -> Vec<Contact> {
         let mut contacts: Vec<Contact> = Vec::new();
         for contact in self.list.iter_mut() {
@@ -45,7 +77,15 @@ impl Bucket {
         contacts
     }
 
+    pub fn contacts(&self) -> Vec<Contact> {
+        self.list.iter().cloned().collect()
+    }
+
     pub fn len(&self) -> usize {
         self.list.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
 }
@@ -7,6 +7,7 @@ use crate::{kademlia::Kademlia, kademlia_id::KademliaID};
 pub enum Command {
     GET(String),
     PUT(String),
+    DELETE(String),
     //    FINDNODE(String),
     EXIT,
 }
@@ -58,10 +59,10 @@ impl Cli {
             Command::GET(hash) => {
                 let target_id = KademliaID::from_hex(hash);
                 match self.kademlia.iterative_find_value(target_id).await {
-                    Ok(Some(_)) => {
+                    Ok(report) if report.result.is_some() => {
                         // continue
                     }
-                    Ok(None) => {
+                    Ok(_) => {
                         println!("Value not found.");
                     }
                     Err(err) => {
@@ -73,10 +74,34 @@ impl Cli {
             Command::PUT(data) => {
                 let target_id = KademliaID::from_data(&data);
                 println!("Data will be stored with key: {}", target_id.to_hex());
-                self.kademlia
-                    .iterative_store(target_id, data)
-                    .await
-                    .unwrap();
+                match self.kademlia.iterative_store(target_id, data).await {
+                    Ok(report) => {
+                        println!(
+                            "Stored on {} of {} nodes.",
+                            report.stored.len(),
+                            report.stored.len() + report.failed.len()
+                        );
+                    }
+                    Err(err) => {
+                        println!("Error storing value: {}", err);
+                    }
+                }
+                CMDStatus::CONTINUE
+            }
+            Command::DELETE(hash) => {
+                let target_id = KademliaID::from_hex(hash);
+                match self.kademlia.iterative_delete(target_id).await {
+                    Ok(report) => {
+                        println!(
+                            "Deleted from {} nodes, {} did not answer.",
+                            report.deleted.len(),
+                            report.failed.len()
+                        );
+                    }
+                    Err(err) => {
+                        println!("Error deleting value: {}", err);
+                    }
+                }
                 CMDStatus::CONTINUE
             }
             /* Command::FINDNODE(target_id_hex) => {
@@ -118,6 +143,13 @@ impl Cli {
                     Err("PUT: missing data argument")
                 }
             }
+            "delete" => {
+                if let Some(arg) = parts.next() {
+                    Ok(Command::DELETE(arg.to_string()))
+                } else {
+                    Err("DELETE: missing hash argument")
+                }
+            }
             /*            "findnode" => {
                 if let Some(arg) = parts.next() {
                     Ok(Command::FINDNODE(arg.to_string()))
@@ -0,0 +1,453 @@
+use {
+    crate::{
+        constants::{
+            ALL_INTERFACES, ALPHA, BUCKET_SIZE, DATA_DIR, DEFAULT_HTTP_PORT, DEFAULT_PORT,
+            DISJOINT_PATHS, DYNAMIC_PUZZLE_DIFFICULTY, MAX_BUCKET_CONTACTS_PER_IP,
+            MAX_BUCKET_CONTACTS_PER_SUBNET, MAX_CONCURRENT_HANDLERS, MAX_CONTACTS_PER_IP,
+            MAX_CONTACTS_PER_SUBNET, MAX_PUZZLE_DIFFICULTY, MAX_STORES_PER_PEER,
+            MAX_STORES_PER_SUBNET, MAX_STORE_SIZE, RATE_BURST, RATE_LIMIT, RPC_TIMEOUT_MS,
+            STALL_TIMEOUT_MS, STATE_DIR, STATIC_PUZZLE_DIFFICULTY, STORE_QUORUM,
+        },
+        utils,
+    },
+    ed25519_dalek::SigningKey,
+    serde::Deserialize,
+    std::{
+        env, fs,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        path::PathBuf,
+        time::Duration,
+    },
+};
+
+pub const USAGE: &str = "\
+usage: kadrustlia [--config <file>] [options]
+
+options (also settable as KAD_<OPTION> env vars or in the TOML config file):
+  --node-key <hex>            use this ed25519 secret key instead of the persisted
+                              one, the node id is derived from it
+  --regenerate-id             throw away the persisted node key and id
+  --bind-address <ip>         address to listen on for RPCs (default ::, which
+                              takes IPv4 as well)
+  --port <port>               RPC port (default 5678)
+  --advertise-address <ip>    address other nodes should use to reach us, without
+                              it and --advertise-port it is learned from peers
+  --advertise-port <port>     port other nodes should use to reach us
+  --bootstrap <host[:port],..> boot nodes, no boot nodes means we are the boot node
+  --k <n>                     bucket size and replication factor
+  --alpha <n>                 lookup parallelism
+  --rpc-timeout-ms <ms>       how long to wait for an RPC response
+  --stall-timeout-ms <ms>     after this long a lookup stops waiting on a peer
+                              before querying another one
+  --store-quorum <n>          replicas that must acknowledge a store (default 1)
+  --disjoint-paths <d>        run lookups over d node-disjoint paths (default 1)
+  --static-puzzle-difficulty <bits>
+                              leading zero bits of H(node id), 0 turns it off
+  --dynamic-puzzle-difficulty <bits>
+                              leading zero bits of H(node id ^ nonce), 0 turns it off
+  --rate-limit <n>            requests per second accepted from one IP (default 100)
+  --rate-burst <n>            requests one IP may send at once (default 200)
+  --max-concurrent-handlers <n>
+                              requests handled at the same time (default 64)
+  --max-store-size <bytes>    largest value accepted in a STORE (default 16384)
+  --max-stores-per-peer <n>   keys one peer may store with us (default 1000)
+  --max-stores-per-subnet <n> keys peers in one /24 or /64 may store with us (default 10000)
+  --max-contacts-per-ip <n>   routing table contacts sharing one IP, 0 for no limit
+  --max-contacts-per-subnet <n>
+                              routing table contacts sharing a /24 (/64), 0 for no limit
+  --max-bucket-contacts-per-ip <n>
+                              the same for every single bucket
+  --max-bucket-contacts-per-subnet <n>
+                              the same for every single bucket
+  --encrypt                   encrypt all traffic we send and only accept
+                              encrypted requests
+  --prefer-tcp                send every request over a stream, for networks
+                              that block UDP
+  --client                    only get and put values, serve no RPCs and stay
+                              out of other nodes' routing tables
+  --state-dir <dir>           where the node id and routing table are kept
+  --storage-dir <dir>         where stored values are kept
+  --http-port <port>          REST interface port (default 3000)";
+
+const OPTIONS: &[&str] = &[
+    "node-key",
+    "regenerate-id",
+    "bind-address",
+    "port",
+    "advertise-address",
+    "advertise-port",
+    "bootstrap",
+    "k",
+    "alpha",
+    "rpc-timeout-ms",
+    "stall-timeout-ms",
+    "store-quorum",
+    "disjoint-paths",
+    "static-puzzle-difficulty",
+    "dynamic-puzzle-difficulty",
+    "rate-limit",
+    "rate-burst",
+    "max-concurrent-handlers",
+    "max-store-size",
+    "max-stores-per-peer",
+    "max-stores-per-subnet",
+    "max-contacts-per-ip",
+    "max-contacts-per-subnet",
+    "max-bucket-contacts-per-ip",
+    "max-bucket-contacts-per-subnet",
+    "encrypt",
+    "prefer-tcp",
+    "client",
+    "state-dir",
+    "storage-dir",
+    "http-port",
+];
+
+const FLAGS: &[&str] = &["regenerate-id", "encrypt", "prefer-tcp", "client"];
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub node_key: Option<String>,
+    pub regenerate_id: bool,
+    pub bind_address: IpAddr,
+    pub port: u16,
+    pub advertise_address: Option<IpAddr>,
+    pub advertise_port: Option<u16>,
+    pub bootstrap: Vec<String>,
+    pub k: usize,
+    pub alpha: usize,
+    pub rpc_timeout_ms: u64,
+    pub stall_timeout_ms: u64,
+    pub store_quorum: usize,
+    pub disjoint_paths: usize,
+    pub static_puzzle_difficulty: u32,
+    pub dynamic_puzzle_difficulty: u32,
+    pub rate_limit: u32,
+    pub rate_burst: u32,
+    pub max_concurrent_handlers: usize,
+    pub max_store_size: usize,
+    pub max_stores_per_peer: usize,
+    pub max_stores_per_subnet: usize,
+    pub max_contacts_per_ip: usize,
+    pub max_contacts_per_subnet: usize,
+    pub max_bucket_contacts_per_ip: usize,
+    pub max_bucket_contacts_per_subnet: usize,
+    pub encrypt: bool,
+    pub prefer_tcp: bool,
+    pub client: bool,
+    pub state_dir: PathBuf,
+    pub storage_dir: PathBuf,
+    pub http_port: u16,
+}
+
+#[derive(Clone, Debug)]
+pub struct KademliaConfig {
+    pub advertise_address: SocketAddr,
+    pub discover_address: bool,
+    pub boot_nodes: Vec<String>,
+    pub k: usize,
+    pub alpha: usize,
+    pub rpc_timeout: Duration,
+    pub stall_timeout: Duration,
+    pub store_quorum: usize,
+    pub disjoint_paths: usize,
+    pub static_puzzle_difficulty: u32,
+    pub dynamic_puzzle_difficulty: u32,
+    pub rate_limit: u32,
+    pub rate_burst: u32,
+    pub max_concurrent_handlers: usize,
+    pub max_store_size: usize,
+    pub max_stores_per_peer: usize,
+    pub max_stores_per_subnet: usize,
+    pub max_contacts_per_ip: usize,
+    pub max_contacts_per_subnet: usize,
+    pub max_bucket_contacts_per_ip: usize,
+    pub max_bucket_contacts_per_subnet: usize,
+    pub encrypt: bool,
+    pub prefer_tcp: bool,
+    pub client: bool,
+    pub storage_dir: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            node_key: None,
+            regenerate_id: false,
+            bind_address: ALL_INTERFACES,
+            port: DEFAULT_PORT,
+            advertise_address: None,
+            advertise_port: None,
+            bootstrap: Vec::new(),
+            k: BUCKET_SIZE,
+            alpha: ALPHA,
+            rpc_timeout_ms: RPC_TIMEOUT_MS,
+            stall_timeout_ms: STALL_TIMEOUT_MS,
+            store_quorum: STORE_QUORUM,
+            disjoint_paths: DISJOINT_PATHS,
+            static_puzzle_difficulty: STATIC_PUZZLE_DIFFICULTY,
+            dynamic_puzzle_difficulty: DYNAMIC_PUZZLE_DIFFICULTY,
+            rate_limit: RATE_LIMIT,
+            rate_burst: RATE_BURST,
+            max_concurrent_handlers: MAX_CONCURRENT_HANDLERS,
+            max_store_size: MAX_STORE_SIZE,
+            max_stores_per_peer: MAX_STORES_PER_PEER,
+            max_stores_per_subnet: MAX_STORES_PER_SUBNET,
+            max_contacts_per_ip: MAX_CONTACTS_PER_IP,
+            max_contacts_per_subnet: MAX_CONTACTS_PER_SUBNET,
+            max_bucket_contacts_per_ip: MAX_BUCKET_CONTACTS_PER_IP,
+            max_bucket_contacts_per_subnet: MAX_BUCKET_CONTACTS_PER_SUBNET,
+            encrypt: false,
+            prefer_tcp: false,
+            client: false,
+            state_dir: PathBuf::from(STATE_DIR),
+            storage_dir: PathBuf::from(DATA_DIR),
+            http_port: DEFAULT_HTTP_PORT,
+        }
+    }
+}
+
+impl Default for KademliaConfig {
+    fn default() -> Self {
+        Self {
+            advertise_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_PORT),
+            discover_address: false,
+            boot_nodes: Vec::new(),
+            k: BUCKET_SIZE,
+            alpha: ALPHA,
+            rpc_timeout: Duration::from_millis(RPC_TIMEOUT_MS),
+            stall_timeout: Duration::from_millis(STALL_TIMEOUT_MS),
+            store_quorum: STORE_QUORUM,
+            disjoint_paths: DISJOINT_PATHS,
+            static_puzzle_difficulty: STATIC_PUZZLE_DIFFICULTY,
+            dynamic_puzzle_difficulty: DYNAMIC_PUZZLE_DIFFICULTY,
+            rate_limit: RATE_LIMIT,
+            rate_burst: RATE_BURST,
+            max_concurrent_handlers: MAX_CONCURRENT_HANDLERS,
+            max_store_size: MAX_STORE_SIZE,
+            max_stores_per_peer: MAX_STORES_PER_PEER,
+            max_stores_per_subnet: MAX_STORES_PER_SUBNET,
+            max_contacts_per_ip: MAX_CONTACTS_PER_IP,
+            max_contacts_per_subnet: MAX_CONTACTS_PER_SUBNET,
+            max_bucket_contacts_per_ip: MAX_BUCKET_CONTACTS_PER_IP,
+            max_bucket_contacts_per_subnet: MAX_BUCKET_CONTACTS_PER_SUBNET,
+            encrypt: false,
+            prefer_tcp: false,
+            client: false,
+            storage_dir: PathBuf::from(DATA_DIR),
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self, String> {
+        let args: Vec<String> = env::args().skip(1).collect();
+        Self::from_sources(&args, |name| env::var(name).ok())
+    }
+
+    // precedence is config file < environment < command line
+    pub fn from_sources(
+        args: &[String],
+        env_var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, String> {
+        let mut cli_options = parse_args(args)?;
+
+        let config_path = match cli_options.iter().position(|(key, _)| key == "config") {
+            Some(index) => Some(cli_options.remove(index).1),
+            None => env_var("KAD_CONFIG"),
+        };
+
+        let mut config = match config_path {
+            Some(path) => {
+                let contents = fs::read_to_string(&path)
+                    .map_err(|e| format!("failed to read config file '{}': {}", path, e))?;
+                Self::from_toml(&contents)?
+            }
+            None => Self::default(),
+        };
+
+        for option in OPTIONS {
+            if let Some(value) = env_var(&env_name(option)) {
+                config.set(option, &value)?;
+            }
+        }
+
+        for (key, value) in cli_options {
+            config.set(&key, &value)?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self, String> {
+        let mut config: Self =
+            toml::from_str(contents).map_err(|e| format!("invalid config file: {}", e))?;
+        config.bootstrap = utils::parse_boot_node_addresses(&config.bootstrap.join(","));
+        Ok(config)
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "node-key" => self.node_key = Some(value.to_string()),
+            "regenerate-id" => self.regenerate_id = parse_value(key, value)?,
+            "bind-address" => self.bind_address = parse_value(key, value)?,
+            "port" => self.port = parse_value(key, value)?,
+            "advertise-address" => self.advertise_address = Some(parse_value(key, value)?),
+            "advertise-port" => self.advertise_port = Some(parse_value(key, value)?),
+            "bootstrap" => self.bootstrap = utils::parse_boot_node_addresses(value),
+            "k" => self.k = parse_value(key, value)?,
+            "alpha" => self.alpha = parse_value(key, value)?,
+            "rpc-timeout-ms" => self.rpc_timeout_ms = parse_value(key, value)?,
+            "stall-timeout-ms" => self.stall_timeout_ms = parse_value(key, value)?,
+            "store-quorum" => self.store_quorum = parse_value(key, value)?,
+            "disjoint-paths" => self.disjoint_paths = parse_value(key, value)?,
+            "static-puzzle-difficulty" => self.static_puzzle_difficulty = parse_value(key, value)?,
+            "dynamic-puzzle-difficulty" => {
+                self.dynamic_puzzle_difficulty = parse_value(key, value)?
+            }
+            "rate-limit" => self.rate_limit = parse_value(key, value)?,
+            "rate-burst" => self.rate_burst = parse_value(key, value)?,
+            "max-concurrent-handlers" => self.max_concurrent_handlers = parse_value(key, value)?,
+            "max-store-size" => self.max_store_size = parse_value(key, value)?,
+            "max-stores-per-peer" => self.max_stores_per_peer = parse_value(key, value)?,
+            "max-stores-per-subnet" => self.max_stores_per_subnet = parse_value(key, value)?,
+            "max-contacts-per-ip" => self.max_contacts_per_ip = parse_value(key, value)?,
+            "max-contacts-per-subnet" => self.max_contacts_per_subnet = parse_value(key, value)?,
+            "max-bucket-contacts-per-ip" => {
+                self.max_bucket_contacts_per_ip = parse_value(key, value)?
+            }
+            "max-bucket-contacts-per-subnet" => {
+                self.max_bucket_contacts_per_subnet = parse_value(key, value)?
+            }
+            "encrypt" => self.encrypt = parse_value(key, value)?,
+            "prefer-tcp" => self.prefer_tcp = parse_value(key, value)?,
+            "client" => self.client = parse_value(key, value)?,
+            "state-dir" => self.state_dir = PathBuf::from(value),
+            "storage-dir" => self.storage_dir = PathBuf::from(value),
+            "http-port" => self.http_port = parse_value(key, value)?,
+            _ => return Err(format!("unknown option '{}'\n\n{}", key, USAGE)),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(node_key) = &self.node_key {
+            if utils::signing_key_from_hex(node_key).is_none() {
+                return Err("node key is not a 32 byte hex key".to_string());
+            }
+        }
+        if self.k == 0 {
+            return Err("k must be at least 1".to_string());
+        }
+        if self.alpha == 0 {
+            return Err("alpha must be at least 1".to_string());
+        }
+        if self.store_quorum == 0 || self.store_quorum > self.k {
+            return Err("store quorum must be between 1 and k".to_string());
+        }
+        if self.disjoint_paths == 0 || self.disjoint_paths > self.k {
+            return Err("disjoint paths must be between 1 and k".to_string());
+        }
+        if self.rate_limit == 0 || self.rate_burst == 0 || self.max_concurrent_handlers == 0 {
+            return Err(
+                "rate limit, rate burst and concurrent handlers must be at least 1".to_string(),
+            );
+        }
+        if self.static_puzzle_difficulty > MAX_PUZZLE_DIFFICULTY
+            || self.dynamic_puzzle_difficulty > MAX_PUZZLE_DIFFICULTY
+        {
+            return Err(format!(
+                "puzzle difficulty must be at most {}",
+                MAX_PUZZLE_DIFFICULTY
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn node_key(&self) -> Option<SigningKey> {
+        self.node_key
+            .as_deref()
+            .and_then(utils::signing_key_from_hex)
+    }
+
+    pub fn bind_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.port)
+    }
+
+    pub fn http_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.http_port)
+    }
+
+    pub fn kademlia_config(&self) -> KademliaConfig {
+        let advertise_address = self
+            .advertise_address
+            .unwrap_or_else(utils::get_own_address);
+        let advertise_port = self.advertise_port.unwrap_or(self.port);
+
+        KademliaConfig {
+            advertise_address: SocketAddr::new(advertise_address, advertise_port),
+            discover_address: self.advertise_address.is_none() && self.advertise_port.is_none(),
+            boot_nodes: self.bootstrap.clone(),
+            k: self.k,
+            alpha: self.alpha,
+            rpc_timeout: Duration::from_millis(self.rpc_timeout_ms),
+            stall_timeout: Duration::from_millis(self.stall_timeout_ms),
+            store_quorum: self.store_quorum,
+            disjoint_paths: self.disjoint_paths,
+            static_puzzle_difficulty: self.static_puzzle_difficulty,
+            dynamic_puzzle_difficulty: self.dynamic_puzzle_difficulty,
+            rate_limit: self.rate_limit,
+            rate_burst: self.rate_burst,
+            max_concurrent_handlers: self.max_concurrent_handlers,
+            max_store_size: self.max_store_size,
+            max_stores_per_peer: self.max_stores_per_peer,
+            max_stores_per_subnet: self.max_stores_per_subnet,
+            max_contacts_per_ip: self.max_contacts_per_ip,
+            max_contacts_per_subnet: self.max_contacts_per_subnet,
+            max_bucket_contacts_per_ip: self.max_bucket_contacts_per_ip,
+            max_bucket_contacts_per_subnet: self.max_bucket_contacts_per_subnet,
+            encrypt: self.encrypt,
+            prefer_tcp: self.prefer_tcp,
+            client: self.client,
+            storage_dir: self.storage_dir.clone(),
+        }
+    }
+}
+
+fn env_name(option: &str) -> String {
+    format!("KAD_{}", option.replace('-', "_").to_uppercase())
+}
+
+fn parse_value<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value '{}' for {}", value, key))
+}
+
+fn parse_args(args: &[String]) -> Result<Vec<(String, String)>, String> {
+    let mut options = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let Some(option) = arg.strip_prefix("--") else {
+            return Err(format!("unexpected argument '{}'\n\n{}", arg, USAGE));
+        };
+        if option == "help" {
+            return Err(USAGE.to_string());
+        }
+
+        if let Some((key, value)) = option.split_once('=') {
+            options.push((key.to_string(), value.to_string()));
+        } else if FLAGS.contains(&option) {
+            options.push((option.to_string(), "true".to_string()));
+        } else if let Some(value) = args.next() {
+            options.push((option.to_string(), value.clone()));
+        } else {
+            return Err(format!("missing value for --{}", option));
+        }
+    }
+
+    Ok(options)
+}
@@ -1,7 +1,11 @@
+use std::net::{IpAddr, Ipv6Addr};
+
 pub const ID_LENGTH: usize = 20;
 pub const BUCKET_SIZE: usize = 20;
 pub const ALPHA: usize = 3;
 pub const RT_BCKT_SIZE: usize = ID_LENGTH << 3;
+pub const JOIN_ATTEMPTS: u32 = 5;
+pub const JOIN_BACKOFF_MS: u64 = 500;
 
 pub mod rpc {
     use serde::{Deserialize, Serialize};
@@ -13,7 +17,54 @@ pub mod rpc {
         FINDNODE,
         FINDVALUE,
         STORE,
+        DELETE,
     }
 }
 
-pub const ALL_IPV4: &str = "0.0.0.0";
+// binding to :: also takes IPv4 peers, as v4-mapped addresses
+pub const ALL_INTERFACES: IpAddr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+pub const DEFAULT_PORT: u16 = 5678;
+pub const DEFAULT_HTTP_PORT: u16 = 3000;
+pub const RPC_TIMEOUT_MS: u64 = 15000;
+pub const STALL_TIMEOUT_MS: u64 = 2000;
+pub const STORE_QUORUM: usize = 1;
+pub const DISJOINT_PATHS: usize = 1;
+pub const STATIC_PUZZLE_DIFFICULTY: u32 = 0;
+pub const DYNAMIC_PUZZLE_DIFFICULTY: u32 = 0;
+pub const MAX_PUZZLE_DIFFICULTY: u32 = 32;
+pub const RATE_LIMIT: u32 = 100;
+pub const RATE_BURST: u32 = 200;
+pub const MAX_CONCURRENT_HANDLERS: usize = 64;
+pub const MAX_STORE_SIZE: usize = 16 * 1024;
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+pub const MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+pub const MAX_STORES_PER_PEER: usize = 1000;
+pub const MAX_STORES_PER_SUBNET: usize = 10_000;
+// 0 turns a diversity limit off
+pub const MAX_CONTACTS_PER_IP: usize = 0;
+pub const MAX_CONTACTS_PER_SUBNET: usize = 0;
+pub const MAX_BUCKET_CONTACTS_PER_IP: usize = 0;
+pub const MAX_BUCKET_CONTACTS_PER_SUBNET: usize = 0;
+// how far the signed send time of a message may be from our clock, requests
+// are remembered for twice as long so none can be replayed
+pub const REPLAY_WINDOW_SECS: u64 = 60;
+// new sessions a single address may set up per second and in a burst, every
+// one costs a key exchange and memory until it expires
+pub const HANDSHAKE_RATE: u32 = 10;
+pub const HANDSHAKE_BURST: u32 = 50;
+pub const MAX_SEEN_REQUESTS: usize = 100_000;
+// contacts in distinct /24 or /64 subnets that have to see us at a new
+// address before we advertise it
+pub const ADDRESS_VOTES: usize = 3;
+// PINGs we send to check an address a peer advertised, per target IP and in
+// a burst, so PINGs to us can not be reflected at someone else
+pub const PING_BACK_RATE: u32 = 1;
+pub const PING_BACK_BURST: u32 = 5;
+
+pub const DATA_DIR: &str = "data";
+pub const STATE_DIR: &str = "state";
+pub const NODE_KEY_FILE: &str = "node_key";
+// where nodes kept a random id before ids were derived from the node key
+pub const LEGACY_NODE_ID_FILE: &str = "node_id";
+pub const ROUTING_TABLE_FILE: &str = "routing_table.bin";
+pub const ROUTING_TABLE_SAVE_INTERVAL_SECS: u64 = 60;
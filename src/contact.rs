@@ -1,27 +1,36 @@
 use crate::kademlia_id::KademliaID;
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Contact {
     pub id: KademliaID,
-    pub address: String,
+    pub address: SocketAddr,
+    // solution to the dynamic id puzzle, see puzzle.rs
+    pub nonce: u64,
     distance: Option<KademliaID>,
 }
 
 type Contacts = Vec<Contact>;
 
 impl Contact {
-    pub fn new(id: KademliaID, address: String) -> Self {
+    pub fn new(id: KademliaID, address: SocketAddr) -> Self {
+        Self::with_nonce(id, address, 0)
+    }
+
+    pub fn with_nonce(id: KademliaID, address: SocketAddr, nonce: u64) -> Self {
         Self {
             id,
             address,
+            nonce,
             distance: None,
         }
     }
 
-    pub fn contact_from_hex(hex: String, address: String) -> Self {
+    pub fn contact_from_hex(hex: String, address: SocketAddr) -> Self {
         Self {
             id: KademliaID::from_hex(hex),
             address,
+            nonce: 0,
             distance: None,
         }
     }
@@ -39,12 +48,22 @@ impl Contact {
     pub fn less(&self, other: Contact) -> bool {
         self.get_distance().less(&other.get_distance())
     }
+
+    pub fn ip(&self) -> IpAddr {
+        self.address.ip()
+    }
 }
 
 pub struct ContactCandidates {
     contacts: Contacts,
 }
 
+impl Default for ContactCandidates {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ContactCandidates {
     pub fn new() -> Self {
         Self {
@@ -57,14 +76,17 @@ impl ContactCandidates {
     }
 
     pub fn sort(&mut self) {
-        self.contacts
-            .sort_by(|a, b| a.get_distance().cmp(&b.get_distance()));
+        self.contacts.sort_by_key(|a| a.get_distance());
     }
 
     pub fn len(&self) -> usize {
         self.contacts.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.contacts.is_empty()
+    }
+
     pub fn get_contacts(&mut self, count: usize) -> &mut [Contact] {
         let len = self.contacts.len();
         let end = count.min(len);
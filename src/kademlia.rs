@@ -1,473 +1,611 @@
 use {
     crate::{
-        constants::{rpc::Command, ALPHA, BUCKET_SIZE},
+        config::KademliaConfig,
+        constants::{rpc::Command, JOIN_ATTEMPTS, JOIN_BACKOFF_MS},
         contact::Contact,
         kademlia_id::KademliaID,
+        lookup::{FindNode, FindValue, Lookup, LookupReport, LookupStrategy},
         networking::Networking,
+        node_state, puzzle,
+        record::MutableRecord,
         routing_table::RoutingTable,
         routing_table_handler::*,
         rpc::RpcMessage,
-        utils,
     },
-    tokio::sync::mpsc,
+    ed25519_dalek::SigningKey,
+    futures::{
+        future::join_all,
+        stream::{FuturesUnordered, StreamExt},
+    },
+    std::{future::Future, net::SocketAddr, path::Path, time::Duration},
+    tokio::{
+        net::lookup_host,
+        sync::mpsc,
+        time::{sleep_until, Instant},
+    },
 };
 
+#[derive(Debug, Clone)]
+pub struct StoreReport {
+    pub key: KademliaID,
+    pub stored: Vec<Contact>,
+    pub failed: Vec<Contact>,
+    pub quorum: usize,
+}
+
+impl StoreReport {
+    pub fn reached_quorum(&self) -> bool {
+        self.stored.len() >= self.quorum
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeleteReport {
+    pub key: KademliaID,
+    pub deleted: Vec<Contact>,
+    pub refused: Vec<Contact>,
+    pub failed: Vec<Contact>,
+}
+
 #[derive(Clone)]
 pub struct Kademlia {
     pub route_table_tx: mpsc::Sender<RouteTableCMD>,
     pub own_id: KademliaID,
     pub networking: Networking,
+    pub config: KademliaConfig,
+}
+
+impl Default for Kademlia {
+    fn default() -> Self {
+        Self::new(KademliaConfig::default())
+    }
 }
 
 impl Kademlia {
-    pub fn new() -> Self {
-        let kad_id = KademliaID::new();
-        let addr = utils::get_own_address();
-        println!("my addr is {}", addr);
-        let contact: Contact = Contact::new(kad_id, addr);
+    pub fn new(config: KademliaConfig) -> Self {
+        Self::with_config(
+            puzzle::generate_key(config.static_puzzle_difficulty),
+            config,
+        )
+    }
+
+    // the node id is derived from the public half of signing_key, so
+    // nobody can claim an id without holding its key
+    pub fn with_config(signing_key: SigningKey, config: KademliaConfig) -> Self {
+        let kad_id = KademliaID::from_public_key(&signing_key.verifying_key());
+        println!("my addr is {}", config.advertise_address);
+        let networking = Networking::with_config(&config, signing_key);
+        let contact = Contact::with_nonce(kad_id, config.advertise_address, networking.nonce());
         let (tx, rx) = mpsc::channel(32);
-        let initial_contact = contact.clone();
+        let routing_table = RoutingTable::with_config(contact, &config);
         tokio::spawn(async move {
-            let routing_table = RoutingTable::new(initial_contact);
             routing_table_handler(rx, routing_table).await;
         });
 
-        let networking = Networking::new();
-
         Self {
             route_table_tx: tx,
             own_id: kad_id,
             networking,
+            config,
         }
     }
 
-    pub async fn listen(&self, addr: &str) {
+    pub fn own_contact(&self) -> Contact {
+        Contact::with_nonce(
+            self.own_id,
+            self.networking.advertise_address(),
+            self.networking.nonce(),
+        )
+    }
+
+    pub async fn bind(&self, addr: SocketAddr) -> std::io::Result<SocketAddr> {
+        self.networking.bind(addr).await
+    }
+
+    pub async fn listen(&self, addr: SocketAddr) {
         let tx = self.route_table_tx.clone();
         let _ = self.networking.listen_for_rpc(tx, addr).await;
     }
 
     pub async fn join(&self) -> std::io::Result<()> {
-        if utils::check_bn() {
+        if self.config.boot_nodes.is_empty() {
+            println!("No boot nodes configured, running as boot node");
             return Ok(());
         }
-        let adr: String = utils::boot_node_address();
-        let mut boot_node_addr: String;
-        #[cfg(not(feature = "local"))]
-        {
-            let adr = "127.0.0.1".to_string();
-            boot_node_addr = format!("{}:{}", adr, "5678");
-        }
-        #[cfg(feature = "local")]
-        {
-            boot_node_addr = format!("{}:{}", adr, "5678");
+        self.join_via(&self.config.boot_nodes).await
+    }
+
+    pub async fn join_via(&self, boot_nodes: &[String]) -> std::io::Result<()> {
+        if boot_nodes.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "no boot nodes configured",
+            ));
         }
+        let own_contact = self.own_contact();
+        let mut backoff = Duration::from_millis(JOIN_BACKOFF_MS);
 
-        println!("Boot node address: {}", boot_node_addr);
+        for attempt in 1..=JOIN_ATTEMPTS {
+            if let Some(boot_node_addr) = self.ping_boot_nodes(boot_nodes, &own_contact).await {
+                println!("Joined through boot node {}", boot_node_addr);
 
-        let own_contact = Contact::new(self.own_id.clone(), utils::get_own_address());
+                let contacts = self.iterative_find_node(self.own_id).await?.result;
+                if contacts.is_empty() {
+                    println!("No contacts found during iterative find node.");
+                }
+                // only contacts in the routing table vote on our address, ask
+                // the ones we just found where they see us
+                if self.config.discover_address {
+                    self.ping_contacts(contacts, &own_contact).await;
+                }
+                return Ok(());
+            }
 
-        self.networking
-            .send_rpc_request_await(
-                KademliaID::new(),
-                &boot_node_addr,
-                Command::PING,
-                None,
-                None,
-                Some(vec![own_contact]),
-            )
-            .await
-            .expect("Failed to send PING");
+            if attempt < JOIN_ATTEMPTS {
+                println!(
+                    "No boot node answered (attempt {}/{}), retrying in {:?}",
+                    attempt, JOIN_ATTEMPTS, backoff
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
 
-        let contacts = self.iterative_find_node(self.own_id.clone()).await?;
+        Err(std::io::Error::new(
+            std::io::ErrorKind::NotConnected,
+            "could not reach any boot node",
+        ))
+    }
 
-        if contacts.is_empty() {
-            println!("No contacts found during iterative find node.");
-            ()
+    async fn ping_boot_nodes(
+        &self,
+        boot_nodes: &[String],
+        own_contact: &Contact,
+    ) -> Option<SocketAddr> {
+        let mut seeds: Vec<SocketAddr> = Vec::new();
+        for boot_node in boot_nodes {
+            match lookup_host(boot_node).await {
+                Ok(resolved) => {
+                    for addr in resolved {
+                        if !seeds.contains(&addr) {
+                            seeds.push(addr);
+                        }
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Failed to resolve boot node '{}': {}", boot_node, e);
+                }
+            }
         }
 
-        // print contacts [DONT DELETE]
-        /*let (reply_tx, mut reply_rx) = mpsc::channel::<Vec<Contact>>(1);
-
-        let _ = self
-            .route_table_tx
-            .send(RouteTableCMD::GetClosestNodes(
-                self.own_id.clone(),
-                reply_tx,
-            ))
-            .await;
+        for seed in seeds {
+            println!("Boot node address: {}", seed);
+            let response = self
+                .networking
+                .send_rpc_request_await(
+                    KademliaID::new(),
+                    &seed.to_string(),
+                    Command::PING,
+                    None,
+                    None,
+                    Some(vec![own_contact.clone()]),
+                )
+                .await;
 
-        if let Some(contacts) = reply_rx.recv().await {
-            println!("{:?}", contacts);
-        } else {
-            println!("no conacts from routing table");
-        }*/
+            match response {
+                Ok(Some(_)) => return Some(seed),
+                Ok(None) => println!("Boot node {} did not answer PING", seed),
+                Err(e) => println!("Failed to send PING to boot node {}: {}", seed, e),
+            }
+        }
 
-        Ok(())
+        None
     }
 
-    pub async fn iterative_find_node(
-        &self,
-        target_id: KademliaID,
-    ) -> std::io::Result<Vec<Contact>> {
-        println!(
-            "Starting iterative find node for target ID: {}",
-            target_id.to_hex()
-        );
+    async fn ping_contacts(&self, contacts: Vec<Contact>, own_contact: &Contact) {
+        let mut tasks = vec![];
+        for contact in contacts {
+            let networking = self.networking.clone();
+            let own_contact = own_contact.clone();
+            tasks.push(tokio::spawn(async move {
+                let _ = networking
+                    .send_rpc_request_to_contact(
+                        KademliaID::new(),
+                        &contact,
+                        Command::PING,
+                        None,
+                        None,
+                        Some(vec![own_contact]),
+                    )
+                    .await;
+            }));
+        }
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
 
-        let mut shortlist: Vec<(Contact, bool)> = Vec::new();
+    pub async fn join_from_state(&self, state_dir: &Path) -> std::io::Result<()> {
+        match self.restore_routing_table(state_dir).await {
+            Ok(0) => {}
+            Ok(alive) => {
+                println!("Restored {} contacts from previous run", alive);
+                self.iterative_find_node(self.own_id).await?;
+                return Ok(());
+            }
+            Err(e) => {
+                eprintln!("Failed to restore routing table: {}", e);
+            }
+        }
+        self.join().await
+    }
 
+    pub async fn save_routing_table(&self, state_dir: &Path) -> std::io::Result<()> {
         let (reply_tx, mut reply_rx) = mpsc::channel::<Vec<Contact>>(1);
         let _ = self
             .route_table_tx
-            .send(RouteTableCMD::GetClosestNodes(target_id.clone(), reply_tx))
+            .send(RouteTableCMD::GetAllContacts(reply_tx))
             .await;
 
-        if let Some(initial_contacts) = reply_rx.recv().await {
-            for contact in initial_contacts.into_iter().take(BUCKET_SIZE) {
-                shortlist.push((contact, false));
-            }
-        }
-
-        let mut closest_node_seen = None;
-        let mut closest_distance = self.own_id.distance(&target_id);
-
-        while !shortlist.is_empty() {
-            let unqueried_contacts: Vec<Contact> = shortlist
-                .iter()
-                .filter(|(_, queried)| !queried)
-                .take(BUCKET_SIZE)
-                .map(|(contact, _)| contact.clone())
-                .collect();
+        let contacts = reply_rx.recv().await.unwrap_or_default();
+        node_state::save_routing_table(state_dir, &contacts).await
+    }
 
-            if unqueried_contacts.is_empty() {
-                println!("No more unqueried contacts left in shortlist. Ending lookup.");
-                break;
-            }
+    pub async fn restore_routing_table(&self, state_dir: &Path) -> std::io::Result<usize> {
+        let saved_contacts = node_state::load_routing_table(state_dir).await?;
+        let own_contact = self.own_contact();
 
-            let mut tasks = vec![];
-            for contact in unqueried_contacts.iter().take(ALPHA) {
-                println!("Querying contact: {}", contact.id.to_hex());
-                let target_addr = format!("{}:{}", contact.address, "5678");
-                let target_id_copy = target_id.clone();
-                let networking_clone = self.networking.clone();
-                let contact_clone = contact.clone();
-                let rpc_id = KademliaID::new();
-
-                let task = tokio::spawn(async move {
-                    let response = networking_clone
-                        .send_rpc_request_await(
-                            rpc_id,
-                            &target_addr,
-                            Command::FINDNODE,
-                            Some(target_id_copy),
-                            None,
-                            None,
-                        )
-                        .await;
-                    (response, contact_clone)
-                });
-
-                tasks.push(task);
+        let mut tasks = vec![];
+        for contact in saved_contacts {
+            if contact.id == self.own_id {
+                continue;
             }
+            let networking_clone = self.networking.clone();
+            let own_contact_clone = own_contact.clone();
+
+            let task = tokio::spawn(async move {
+                let response = networking_clone
+                    .send_rpc_request_to_contact(
+                        KademliaID::new(),
+                        &contact,
+                        Command::PING,
+                        None,
+                        None,
+                        Some(vec![own_contact_clone]),
+                    )
+                    .await;
+                (response, contact)
+            });
+            tasks.push(task);
+        }
 
-            for task in tasks {
-                match task.await {
-                    Ok((
-                        Ok(Some(RpcMessage::Response {
-                            contact: Some(received_contacts),
-                            ..
-                        })),
-                        queried_contact,
-                    )) => {
-                        println!(
-                            "Received response from contact: {}",
-                            queried_contact.id.to_hex()
-                        );
-
-                        for new_contact in received_contacts {
-                            println!(
-                                "Received new contact: {} from response",
-                                new_contact.id.to_hex()
-                            );
-                            let distance = new_contact.id.distance(&target_id);
-
-                            if distance.less(&closest_distance) {
-                                println!(
-                                    "Found closer contact: {} with distance {}",
-                                    new_contact.id.to_hex(),
-                                    distance.to_hex()
-                                );
-                                closest_node_seen = Some(new_contact.clone());
-                                closest_distance = distance;
-                            }
-
-                            if !shortlist.iter().any(|(c, _)| c.id == new_contact.id) {
-                                println!(
-                                    "Adding new contact: {} to shortlist",
-                                    new_contact.id.to_hex()
-                                );
-                                shortlist.push((new_contact, false));
-                            }
-                        }
-                    }
-                    Ok((Ok(Some(_)), queried_contact)) => {
-                        println!(
-                            "Received response from contact: {} but no contacts",
-                            queried_contact.id.to_hex()
-                        );
-                    }
-                    Ok((Ok(None), queried_contact)) => {
-                        println!(
-                            "No response from contact: {} within timeout. Marking as unreachable.",
-                            queried_contact.id.to_hex()
-                        );
-                        shortlist.retain(|(contact, _)| contact.id != queried_contact.id);
-                    }
-                    Ok((Err(e), queried_contact)) => {
-                        println!(
-                            "Failed to send request to contact: {}. Error: {}. Marking as unreachable.",
-                            queried_contact.id.to_hex(),
-                            e
-                        );
-                        shortlist.retain(|(contact, _)| contact.id != queried_contact.id);
-                    }
-                    Err(e) => {
-                        println!("Task failed with error: {}", e);
-                    }
+        let mut alive = 0;
+        for task in tasks {
+            match task.await {
+                // the listener adds whoever signed the PONG, a saved contact
+                // whose address now belongs to another node is not put back
+                Ok((Ok(Some(RpcMessage::Response { .. })), _)) => alive += 1,
+                Ok((_, contact)) => {
+                    println!("Saved contact {} did not answer PING", contact.id.to_hex());
                 }
-            }
-
-            for contact in &mut shortlist {
-                if unqueried_contacts.iter().any(|c| c.id == contact.0.id) {
-                    contact.1 = true;
+                Err(e) => {
+                    println!("Task failed with error: {}", e);
                 }
             }
-
-            let active_contacts: Vec<_> = shortlist
-                .iter()
-                .filter(|(_, queried)| *queried)
-                .map(|(contact, _)| contact.clone())
-                .collect();
-
-            if active_contacts.len() >= BUCKET_SIZE {
-                /*println!(
-                    "Found {} active contacts, ending lookup.",
-                    active_contacts.len()
-                );*/
-                return Ok(active_contacts);
-            }
-
-            if unqueried_contacts.is_empty() || closest_node_seen.is_none() {
-                println!("No improvement found or no more unqueried contacts. Ending lookup.");
-                break;
-            }
         }
 
-        let mut active_contacts: Vec<_> = shortlist
-            .iter()
-            .filter(|(_, queried)| *queried)
-            .map(|(contact, _)| {
-                let mut c = contact.clone();
-                c.calc_distance(&target_id);
-                c
-            })
-            .collect();
+        Ok(alive)
+    }
 
-        active_contacts.sort_by(|a, b| a.get_distance().cmp(&b.get_distance()));
+    pub async fn iterative_find_node(
+        &self,
+        target_id: KademliaID,
+    ) -> std::io::Result<LookupReport<Vec<Contact>>> {
+        println!(
+            "Starting iterative find node for target ID: {}",
+            target_id.to_hex()
+        );
+
+        let lookup = self.new_lookup(FindNode, target_id).await;
+        let report = self.run_lookup(lookup).await;
 
-        /*println!(
+        println!(
             "Finished iterative find node. Found {} active contacts.",
-            active_contacts.len()
-        );*/
-        Ok(active_contacts)
+            report.result.len()
+        );
+        Ok(report)
     }
 
     pub async fn iterative_find_value(
         &self,
         target_id: KademliaID,
-    ) -> std::io::Result<Option<String>> {
+    ) -> std::io::Result<LookupReport<Option<String>>> {
         println!(
             "Starting iterative find value for target ID: {}",
             target_id.to_hex()
         );
 
-        let mut shortlist: Vec<(Contact, bool)> = Vec::new();
-
-        let (reply_tx, mut reply_rx) = mpsc::channel::<Vec<Contact>>(1);
-        let _ = self
-            .route_table_tx
-            .send(RouteTableCMD::GetClosestNodes(target_id.clone(), reply_tx))
-            .await;
+        let lookup = self.new_lookup(FindValue::new(target_id), target_id).await;
+        Ok(self.run_lookup(lookup).await)
+    }
 
-        if let Some(initial_contacts) = reply_rx.recv().await {
-            for contact in initial_contacts.into_iter().take(BUCKET_SIZE) {
-                shortlist.push((contact, false));
-            }
-        }
+    async fn new_lookup<S: LookupStrategy>(&self, strategy: S, target_id: KademliaID) -> Lookup<S> {
+        let initial_contacts = self.closest_known_contacts(target_id).await;
+        Lookup::new(
+            strategy,
+            self.own_id,
+            target_id,
+            &self.config,
+            initial_contacts,
+        )
+    }
 
-        while !shortlist.is_empty() {
-            let unqueried_contacts: Vec<Contact> = shortlist
-                .iter()
-                .filter(|(_, queried)| !queried)
-                .take(BUCKET_SIZE)
-                .map(|(contact, _)| contact.clone())
-                .collect();
+    // sends the queries the lookup asks for and feeds the answers back
+    // until it is done
+    async fn run_lookup<S: LookupStrategy>(
+        &self,
+        mut lookup: Lookup<S>,
+    ) -> LookupReport<S::Output> {
+        let mut pending = FuturesUnordered::new();
 
-            if unqueried_contacts.is_empty() {
-                println!("No more unqueried contacts left in shortlist. Ending lookup.");
-                break;
+        loop {
+            for contact in lookup.next_queries(Instant::now()) {
+                pending.push(self.query(contact, lookup.command(), lookup.target_id()));
             }
 
-            let mut tasks = vec![];
-            for contact in unqueried_contacts.iter().take(ALPHA) {
-                println!("Querying contact: {}", contact.id.to_hex());
-                let target_addr = format!("{}:{}", contact.address, "5678");
-                let target_id_copy = target_id.clone();
-                let networking_clone = self.networking.clone();
-                let contact_clone = contact.clone();
-                let rpc_id = KademliaID::new();
-
-                let task = tokio::spawn(async move {
-                    let response = networking_clone
-                        .send_rpc_request_await(
-                            rpc_id,
-                            &target_addr,
-                            Command::FINDVALUE,
-                            Some(target_id_copy),
-                            None,
-                            None,
-                        )
-                        .await;
-                    (response, contact_clone)
-                });
-
-                tasks.push(task);
+            if lookup.is_finished() {
+                println!("Lookup finished.");
+                break;
             }
 
-            for task in tasks {
-                match task.await {
-                    Ok((
-                        Ok(Some(RpcMessage::Response {
-                            data: Some(value), ..
-                        })),
-                        queried_contact,
-                    )) => {
-                        println!(
-                            "Value found: {} from node {}",
-                            value,
-                            queried_contact.id.to_hex(),
-                        );
-                        return Ok(Some(value));
-                    }
-                    Ok((
-                        Ok(Some(RpcMessage::Response {
-                            contact: Some(received_contacts),
-                            ..
-                        })),
-                        queried_contact,
-                    )) => {
-                        println!(
-                            "Received contacts from contact: {}",
-                            queried_contact.id.to_hex()
-                        );
-
-                        for new_contact in received_contacts {
-                            if !shortlist.iter().any(|(c, _)| c.id == new_contact.id) {
-                                println!(
-                                    "Adding new contact: {} to shortlist",
-                                    new_contact.id.to_hex()
-                                );
-                                shortlist.push((new_contact, false));
-                            }
-                        }
-                    }
-                    Ok((Ok(Some(_)), queried_contact)) => {
-                        println!(
-                            "Received response from contact: {} but no data or contacts",
-                            queried_contact.id.to_hex()
-                        );
-                    }
-                    Ok((Ok(None), queried_contact)) => {
-                        println!(
-                            "No response from contact: {} within timeout. Marking as unreachable.",
-                            queried_contact.id.to_hex()
-                        );
-                        shortlist.retain(|(contact, _)| contact.id != queried_contact.id);
-                    }
-                    Ok((Err(e), queried_contact)) => {
-                        println!(
-                            "Failed to send request to contact: {}. Error: {}. Marking as unreachable.",
-                            queried_contact.id.to_hex(),
-                            e
-                        );
-                        shortlist.retain(|(contact, _)| contact.id != queried_contact.id);
-                    }
-                    Err(e) => {
-                        println!("Task failed with error: {}", e);
-                    }
+            let stall_deadline = lookup
+                .next_stall_deadline()
+                .unwrap_or_else(|| Instant::now() + self.config.rpc_timeout);
+            let (queried_contact, result) = tokio::select! {
+                Some(result) = pending.next() => result,
+                _ = sleep_until(stall_deadline) => continue,
+            };
+
+            match result {
+                // a peer that refuses us has not answered the query
+                Ok(Some(RpcMessage::Error { message, .. })) => {
+                    println!(
+                        "Contact {} refused the query: {}",
+                        queried_contact.id.to_hex(),
+                        message
+                    );
+                    lookup.on_failure(&queried_contact);
                 }
-            }
-
-            for contact in &mut shortlist {
-                if unqueried_contacts.iter().any(|c| c.id == contact.0.id) {
-                    contact.1 = true;
+                Ok(Some(response)) => {
+                    println!(
+                        "Received response from contact: {}",
+                        queried_contact.id.to_hex()
+                    );
+                    lookup.on_response(&queried_contact, response);
+                }
+                Ok(None) => lookup.on_failure(&queried_contact),
+                Err(e) => {
+                    println!(
+                        "Failed to send request to contact: {}. Error: {}",
+                        queried_contact.id.to_hex(),
+                        e
+                    );
+                    lookup.on_failure(&queried_contact);
                 }
             }
+        }
 
-            if unqueried_contacts.is_empty() {
-                println!("No more unqueried contacts and value not found. Ending lookup.");
-                break;
-            }
+        let target_id = lookup.target_id();
+        let report = lookup.finish();
+
+        // peers that served bad data are not asked again
+        for contact in &report.rejected {
+            let _ = self
+                .route_table_tx
+                .send(RouteTableCMD::RemoveContact(contact.id))
+                .await;
         }
+        println!(
+            "Lookup for {} took {:?}: {} rounds, {} queried, {} responsive, {} timed out, {} rejected",
+            target_id.to_hex(),
+            report.elapsed,
+            report.rounds,
+            report.queried.len(),
+            report.responsive.len(),
+            report.timed_out.len(),
+            report.rejected.len()
+        );
+        report
+    }
 
-        println!("Value not found in the network.");
-        Ok(None)
+    async fn closest_known_contacts(&self, target_id: KademliaID) -> Vec<Contact> {
+        let (reply_tx, mut reply_rx) = mpsc::channel::<Vec<Contact>>(1);
+        let _ = self
+            .route_table_tx
+            .send(RouteTableCMD::GetClosestNodes(target_id, reply_tx))
+            .await;
+
+        reply_rx
+            .recv()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .take(self.config.k)
+            .collect()
+    }
+
+    fn query(
+        &self,
+        contact: Contact,
+        cmd: Command,
+        target_id: KademliaID,
+    ) -> impl Future<Output = (Contact, std::io::Result<Option<RpcMessage>>)> {
+        println!("Querying contact: {}", contact.id.to_hex());
+        let networking = self.networking.clone();
+        async move {
+            let response = networking
+                .send_rpc_request_to_contact(
+                    KademliaID::new(),
+                    &contact,
+                    cmd,
+                    Some(target_id),
+                    None,
+                    None,
+                )
+                .await;
+            (contact, response)
+        }
     }
 
     pub async fn iterative_store(
         &self,
         target_id: KademliaID,
         data: String,
-    ) -> std::io::Result<()> {
+    ) -> std::io::Result<StoreReport> {
         println!(
             "Starting iterative store for target ID: {}",
             target_id.to_hex()
         );
 
-        let closest_nodes = self.iterative_find_node(target_id.clone()).await?;
+        let closest_nodes = self.iterative_find_node(target_id).await?.result;
 
         if closest_nodes.is_empty() {
             println!("No contacts found to store data.");
-            return Ok(());
         }
 
-        for contact in closest_nodes {
-            let target_addr = format!("{}:{}", contact.address, "5678");
-            println!(
-                "Storing data at contact: {} ({})",
-                contact.id.to_hex(),
-                target_addr
-            );
-
-            let store_result = self
-                .networking
-                .send_rpc_request_await(
-                    KademliaID::new(),
-                    &target_addr,
-                    Command::STORE,
-                    Some(target_id.clone()),
-                    Some(data.clone()),
-                    None,
-                )
-                .await;
+        let responses = self
+            .send_to_replicas(closest_nodes, Command::STORE, target_id, Some(data))
+            .await;
 
+        let mut report = StoreReport {
+            key: target_id,
+            stored: Vec::new(),
+            failed: Vec::new(),
+            quorum: self.config.store_quorum,
+        };
+        for (contact, store_result) in responses {
             match store_result {
-                Ok(Some(_)) => println!("Successfully stored data at {}", contact.id.to_hex()),
-                Ok(None) => println!("No response from {}", contact.id.to_hex()),
-                Err(e) => println!("Failed to store data at {}: {}", contact.id.to_hex(), e),
+                Ok(Some(RpcMessage::Response { .. })) => {
+                    println!("Successfully stored data at {}", contact.id.to_hex());
+                    report.stored.push(contact);
+                }
+                Ok(_) => {
+                    println!("No acknowledgement from {}", contact.id.to_hex());
+                    report.failed.push(contact);
+                }
+                Err(e) => {
+                    println!("Failed to store data at {}: {}", contact.id.to_hex(), e);
+                    report.failed.push(contact);
+                }
+            }
+        }
+
+        if !report.reached_quorum() {
+            return Err(std::io::Error::other(format!(
+                "store quorum not reached: {} of {} replicas acknowledged, {} required",
+                report.stored.len(),
+                report.stored.len() + report.failed.len(),
+                report.quorum
+            )));
+        }
+        Ok(report)
+    }
+
+    // stores value under the hash of the signing key's public key, replicas
+    // only replace what they have with a higher sequence number
+    pub async fn put_record(
+        &self,
+        signing_key: &SigningKey,
+        sequence: u64,
+        value: String,
+    ) -> std::io::Result<StoreReport> {
+        let record = MutableRecord::new(signing_key, sequence, value);
+        self.iterative_store(record.key(), record.encode()).await
+    }
+
+    // only the original publisher of a value or the key a record is signed
+    // with can take it down again
+    pub async fn iterative_delete(&self, target_id: KademliaID) -> std::io::Result<DeleteReport> {
+        println!(
+            "Starting iterative delete for target ID: {}",
+            target_id.to_hex()
+        );
+
+        let closest_nodes = self.iterative_find_node(target_id).await?.result;
+        let responses = self
+            .send_to_replicas(closest_nodes, Command::DELETE, target_id, None)
+            .await;
+
+        let mut report = DeleteReport {
+            key: target_id,
+            deleted: Vec::new(),
+            refused: Vec::new(),
+            failed: Vec::new(),
+        };
+        for (contact, delete_result) in responses {
+            match delete_result {
+                Ok(Some(RpcMessage::Response { .. })) => report.deleted.push(contact),
+                Ok(Some(RpcMessage::Error { message, .. })) => {
+                    println!("{} refused to delete: {}", contact.id.to_hex(), message);
+                    report.refused.push(contact);
+                }
+                Ok(_) => {
+                    println!("No response from {}", contact.id.to_hex());
+                    report.failed.push(contact);
+                }
+                Err(e) => {
+                    println!("Failed to delete data at {}: {}", contact.id.to_hex(), e);
+                    report.failed.push(contact);
+                }
             }
         }
 
-        Ok(())
+        if report.deleted.is_empty() && !report.refused.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!(
+                    "{} replicas refused the delete, only the publisher may delete a value",
+                    report.refused.len()
+                ),
+            ));
+        }
+        Ok(report)
+    }
+
+    // sends the same request to every replica at once, our own contact goes
+    // along so the replicas know who published the value
+    async fn send_to_replicas(
+        &self,
+        replicas: Vec<Contact>,
+        cmd: Command,
+        target_id: KademliaID,
+        data: Option<String>,
+    ) -> Vec<(Contact, std::io::Result<Option<RpcMessage>>)> {
+        let own_contact = self.own_contact();
+        let requests = replicas.into_iter().map(|contact| {
+            let networking = self.networking.clone();
+            let data = data.clone();
+            let own_contact = own_contact.clone();
+            async move {
+                println!(
+                    "Sending {:?} to contact: {} ({})",
+                    cmd,
+                    contact.id.to_hex(),
+                    contact.address
+                );
+                let response = networking
+                    .send_rpc_request_to_contact(
+                        KademliaID::new(),
+                        &contact,
+                        cmd,
+                        Some(target_id),
+                        data,
+                        Some(vec![own_contact]),
+                    )
+                    .await;
+                (contact, response)
+            }
+        });
+        join_all(requests).await
     }
 }
@@ -1,5 +1,6 @@
 use {
     crate::constants::ID_LENGTH,
+    ed25519_dalek::VerifyingKey,
     rand::Rng,
     serde::{Deserialize, Serialize},
     sha2::{Digest, Sha256},
@@ -9,11 +10,17 @@ use {
 
 type KadId = [u8; ID_LENGTH];
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct KademliaID {
     pub id: KadId,
 }
 
+impl Default for KademliaID {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl KademliaID {
     pub fn new() -> Self {
         let mut id: KadId = [0u8; ID_LENGTH];
@@ -22,7 +29,16 @@ impl KademliaID {
     }
 
     pub fn from_data(data: &str) -> Self {
-        let hash = Sha256::digest(data.as_bytes());
+        Self::from_bytes(data.as_bytes())
+    }
+
+    // node ids and mutable record keys are the hash of an ed25519 public key
+    pub fn from_public_key(public_key: &VerifyingKey) -> Self {
+        Self::from_bytes(public_key.as_bytes())
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let hash = Sha256::digest(data);
         let mut id: KadId = [0u8; ID_LENGTH];
         id.copy_from_slice(&hash[..ID_LENGTH]);
         Self { id }
@@ -36,9 +52,7 @@ impl KademliaID {
         let num_full_bytes = num_bits / 8;
         let num_bits_in_partial_byte = num_bits % 8;
 
-        for i in 0..num_full_bytes {
-            id[i] = self.id[i];
-        }
+        id[..num_full_bytes].copy_from_slice(&self.id[..num_full_bytes]);
 
         if num_bits_in_partial_byte > 0 && num_full_bytes < ID_LENGTH {
             let mask = 0xff << (8 - num_bits_in_partial_byte);
@@ -80,6 +94,13 @@ impl KademliaID {
         Self { id }
     }
 
+    pub fn try_from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != ID_LENGTH * 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(Self::from_hex(hex.to_string()))
+    }
+
     pub fn with_id(id: KadId) -> Self {
         Self { id }
     }
@@ -121,7 +142,7 @@ impl KademliaID {
             .iter()
             .zip(other.id.iter())
             .find(|(a, b)| a != b)
-            .map_or(false, |(a, b)| a < b)
+            .is_some_and(|(a, b)| a < b)
     }
 
     pub fn equals(&self, other: &KademliaID) -> bool {
@@ -132,13 +153,6 @@ impl KademliaID {
     }
 }
 
-impl PartialEq for KademliaID {
-    fn eq(&self, other: &Self) -> bool {
-        self.equals(other)
-    }
-}
-impl Eq for KademliaID {}
-
 impl PartialOrd for KademliaID {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
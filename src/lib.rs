@@ -1,12 +1,21 @@
 pub mod bucket;
 pub mod cli;
+pub mod config;
 pub mod constants;
 pub mod contact;
 pub mod kademlia;
 pub mod kademlia_id;
+pub mod limits;
+pub mod lookup;
+pub mod nat;
 pub mod networking;
+pub mod node_state;
+pub mod puzzle;
+pub mod record;
 pub mod routing_table;
 pub mod routing_table_handler;
 pub mod rpc;
+pub mod session;
+pub mod storage;
 pub mod tests;
 pub mod utils;
@@ -0,0 +1,192 @@
+use {
+    crate::{contact::Contact, kademlia_id::KademliaID},
+    std::{
+        collections::{HashMap, HashSet},
+        hash::Hash,
+        net::IpAddr,
+        time::Instant,
+    },
+};
+
+// above this many tracked addresses the ones with a full bucket are forgotten
+const MAX_TRACKED_ADDRESSES: usize = 10_000;
+const MAX_TRACKED_STORERS: usize = 10_000;
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+// a token bucket per source address, every request takes one token and
+// they refill at rate per second up to burst
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: HashMap<IpAddr, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst as f64,
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn allow(&mut self, addr: IpAddr, now: Instant) -> bool {
+        if self.buckets.len() >= MAX_TRACKED_ADDRESSES && !self.buckets.contains_key(&addr) {
+            self.forget_idle(now);
+        }
+
+        let burst = self.burst;
+        let rate = self.rate;
+        let bucket = self.buckets.entry(addr).or_insert(TokenBucket {
+            tokens: burst,
+            last_refill: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn forget_idle(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.last_refill);
+            bucket.tokens + elapsed.as_secs_f64() * rate < burst
+        });
+    }
+}
+
+// distinct keys stored per peer or subnet, above MAX_TRACKED_STORERS the
+// half that stored the fewest is forgotten
+struct KeyCounts<T> {
+    max_keys: usize,
+    keys: HashMap<T, HashSet<KademliaID>>,
+}
+
+impl<T: Eq + Hash + Copy> KeyCounts<T> {
+    fn allows(&self, storer: &T, key: &KademliaID) -> bool {
+        match self.keys.get(storer) {
+            Some(keys) => keys.contains(key) || keys.len() < self.max_keys,
+            None => self.max_keys > 0,
+        }
+    }
+
+    fn add(&mut self, storer: T, key: KademliaID) {
+        if self.keys.len() >= MAX_TRACKED_STORERS && !self.keys.contains_key(&storer) {
+            // forget the half that stored the least in one pass
+            let mut counts: Vec<usize> = self.keys.values().map(HashSet::len).collect();
+            let middle = counts.len() / 2;
+            let threshold = *counts.select_nth_unstable(middle).1;
+            let mut evicted = 0;
+            self.keys.retain(|_, keys| {
+                let keep = keys.len() > threshold || evicted >= middle.max(1);
+                if !keep {
+                    evicted += 1;
+                }
+                keep
+            });
+        }
+        self.keys.entry(storer).or_default().insert(key);
+    }
+
+    fn remove(&mut self, storer: &T, key: &KademliaID) {
+        if let Some(keys) = self.keys.get_mut(storer) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.keys.remove(storer);
+            }
+        }
+    }
+}
+
+// which keys every peer and every subnet has stored with us, storing the
+// same key again does not count twice. ids cost nothing without puzzles,
+// the subnet quota is what holds then
+pub struct StoreQuota {
+    peers: KeyCounts<KademliaID>,
+    subnets: KeyCounts<IpAddr>,
+}
+
+impl StoreQuota {
+    pub fn new(max_keys_per_peer: usize, max_keys_per_subnet: usize) -> Self {
+        Self {
+            peers: KeyCounts {
+                max_keys: max_keys_per_peer,
+                keys: HashMap::new(),
+            },
+            subnets: KeyCounts {
+                max_keys: max_keys_per_subnet,
+                keys: HashMap::new(),
+            },
+        }
+    }
+
+    pub fn allows(&self, peer: &KademliaID, ip: IpAddr, key: &KademliaID) -> bool {
+        self.peers.allows(peer, key) && self.subnets.allows(&subnet(ip), key)
+    }
+
+    pub fn add(&mut self, peer: KademliaID, ip: IpAddr, key: KademliaID) {
+        self.peers.add(peer, key);
+        self.subnets.add(subnet(ip), key);
+    }
+
+    pub fn remove(&mut self, peer: &KademliaID, ip: IpAddr, key: &KademliaID) {
+        self.peers.remove(peer, key);
+        self.subnets.remove(&subnet(ip), key);
+    }
+
+    pub fn tracked_storers(&self) -> usize {
+        self.peers.keys.len()
+    }
+}
+
+// how many contacts may share an IP or a subnet (/24 for IPv4, /64 for
+// IPv6), 0 means no limit
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DiversityLimits {
+    pub per_ip: usize,
+    pub per_subnet: usize,
+}
+
+impl DiversityLimits {
+    pub fn admits<'a>(
+        &self,
+        existing: impl Iterator<Item = &'a Contact>,
+        contact: &Contact,
+    ) -> bool {
+        if self.per_ip == 0 && self.per_subnet == 0 {
+            return true;
+        }
+        let ip = contact.ip();
+
+        let (mut same_ip, mut same_subnet) = (0, 0);
+        for other in existing.filter(|c| c.id != contact.id) {
+            let other_ip = other.ip();
+            if other_ip == ip {
+                same_ip += 1;
+            }
+            if subnet(other_ip) == subnet(ip) {
+                same_subnet += 1;
+            }
+        }
+        (self.per_ip == 0 || same_ip < self.per_ip)
+            && (self.per_subnet == 0 || same_subnet < self.per_subnet)
+    }
+}
+
+pub fn subnet(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4((u32::from(ip) & 0xffff_ff00).into()),
+        IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & !((1u128 << 64) - 1)).into()),
+    }
+}
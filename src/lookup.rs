@@ -0,0 +1,504 @@
+use {
+    crate::{
+        config::KademliaConfig, constants::rpc::Command, contact::Contact, kademlia_id::KademliaID,
+        puzzle, record::MutableRecord, rpc::RpcMessage,
+    },
+    std::{collections::HashMap, time::Duration},
+    tokio::time::Instant,
+};
+
+#[derive(Debug, Clone)]
+pub struct LookupReport<T> {
+    pub result: T,
+    // how many hops away from our own routing table the lookup had to go
+    pub rounds: usize,
+    pub queried: Vec<Contact>,
+    pub responsive: Vec<Contact>,
+    pub timed_out: Vec<Contact>,
+    // peers that answered with data that failed verification
+    pub rejected: Vec<Contact>,
+    pub value_from: Option<Contact>,
+    // the closest peer that answered without the value, one that only had
+    // an older record counts
+    pub closest_without_value: Option<Contact>,
+    pub elapsed: Duration,
+}
+
+pub enum Progress<T> {
+    Continue(Vec<Contact>),
+    Done(T),
+    Reject,
+}
+
+// what a lookup sends and how it reads the answers, the Lookup itself
+// only decides whom to ask next and when to stop
+pub trait LookupStrategy {
+    type Output;
+
+    fn command(&self) -> Command;
+    fn interpret(&mut self, from: &Contact, response: RpcMessage) -> Progress<Self::Output>;
+    // the result and the peers it came from, the first one supplied it
+    fn finish(self, closest: Vec<Contact>) -> (Self::Output, Vec<Contact>);
+}
+
+pub struct FindNode;
+
+impl LookupStrategy for FindNode {
+    type Output = Vec<Contact>;
+
+    fn command(&self) -> Command {
+        Command::FINDNODE
+    }
+
+    fn interpret(&mut self, _from: &Contact, response: RpcMessage) -> Progress<Self::Output> {
+        match response {
+            RpcMessage::Response {
+                contact: Some(contacts),
+                ..
+            } => Progress::Continue(contacts),
+            _ => Progress::Continue(Vec::new()),
+        }
+    }
+
+    fn finish(self, closest: Vec<Contact>) -> (Self::Output, Vec<Contact>) {
+        (closest, Vec::new())
+    }
+}
+
+// plain values end the lookup at the first answer, mutable records are
+// collected from every replica on the way and the newest one wins
+pub struct FindValue {
+    target_id: KademliaID,
+    newest: Option<MutableRecord>,
+    // every peer that answered with the newest record, in order
+    holders: Vec<Contact>,
+}
+
+impl FindValue {
+    pub fn new(target_id: KademliaID) -> Self {
+        Self {
+            target_id,
+            newest: None,
+            holders: Vec::new(),
+        }
+    }
+}
+
+impl LookupStrategy for FindValue {
+    type Output = Option<String>;
+
+    fn command(&self) -> Command {
+        Command::FINDVALUE
+    }
+
+    fn interpret(&mut self, from: &Contact, response: RpcMessage) -> Progress<Self::Output> {
+        match response {
+            RpcMessage::Response {
+                data: Some(value), ..
+            } if MutableRecord::is_record(&value) => {
+                match MutableRecord::decode(&value)
+                    .filter(|record| record.key() == self.target_id && record.verify())
+                {
+                    Some(record) => {
+                        println!(
+                            "Record with sequence {} from node {}",
+                            record.sequence,
+                            from.id.to_hex()
+                        );
+                        match self.newest.as_ref().map(|newest| newest.sequence) {
+                            Some(sequence) if sequence > record.sequence => {}
+                            Some(sequence) if sequence == record.sequence => {
+                                self.holders.push(from.clone());
+                            }
+                            _ => {
+                                self.newest = Some(record);
+                                self.holders = vec![from.clone()];
+                            }
+                        }
+                    }
+                    None => {
+                        println!("Invalid record from node {}", from.id.to_hex());
+                        return Progress::Reject;
+                    }
+                }
+                Progress::Continue(Vec::new())
+            }
+            // anything else is content addressed and has to hash to its key
+            RpcMessage::Response {
+                data: Some(value), ..
+            } if KademliaID::from_data(&value) != self.target_id => {
+                println!(
+                    "Value from node {} does not match key {}",
+                    from.id.to_hex(),
+                    self.target_id.to_hex()
+                );
+                Progress::Reject
+            }
+            RpcMessage::Response {
+                data: Some(value), ..
+            } => {
+                println!("Value found: {} from node {}", value, from.id.to_hex());
+                Progress::Done(Some(value))
+            }
+            RpcMessage::Response {
+                contact: Some(contacts),
+                ..
+            } => Progress::Continue(contacts),
+            _ => Progress::Continue(Vec::new()),
+        }
+    }
+
+    fn finish(self, _closest: Vec<Contact>) -> (Self::Output, Vec<Contact>) {
+        if self.newest.is_none() {
+            println!("Value not found in the network.");
+        }
+        (self.newest.map(|record| record.value), self.holders)
+    }
+}
+
+struct Candidate {
+    contact: Contact,
+    // 1 for contacts from our own routing table, +1 for every response
+    // it took to learn about it
+    hop: usize,
+    queried: bool,
+    responded: bool,
+}
+
+// one shortlist of a lookup, with disjoint paths a contact only ever
+// shows up in one of them
+struct LookupPath {
+    // sorted by distance to the target
+    shortlist: Vec<Candidate>,
+    // every query without an answer yet, only the ones that are not
+    // stalled count against the alpha window
+    outstanding: HashMap<KademliaID, Instant>,
+    stalled: Vec<KademliaID>,
+    closest_distance: Option<KademliaID>,
+    unproductive_responses: usize,
+    final_phase: bool,
+}
+
+impl LookupPath {
+    fn new() -> Self {
+        Self {
+            shortlist: Vec::new(),
+            outstanding: HashMap::new(),
+            stalled: Vec::new(),
+            closest_distance: None,
+            unproductive_responses: 0,
+            final_phase: false,
+        }
+    }
+
+    fn contains(&self, kad_id: &KademliaID) -> bool {
+        self.outstanding.contains_key(kad_id)
+            || self.shortlist.iter().any(|c| c.contact.id == *kad_id)
+    }
+
+    fn finish_query(&mut self, kad_id: &KademliaID) -> bool {
+        self.stalled.retain(|stalled_id| stalled_id != kad_id);
+        self.outstanding.remove(kad_id).is_some()
+    }
+
+    fn is_finished(&self, k: usize) -> bool {
+        if self.outstanding.is_empty() {
+            return true;
+        }
+        !self.shortlist.is_empty() && self.shortlist.iter().take(k).all(|c| c.responded)
+    }
+
+    fn add_candidate(&mut self, contact: Contact, hop: usize) {
+        let position = self
+            .shortlist
+            .partition_point(|c| c.contact.get_distance() < contact.get_distance());
+        self.shortlist.insert(
+            position,
+            Candidate {
+                contact,
+                hop,
+                queried: false,
+                responded: false,
+            },
+        );
+    }
+
+    fn current_closest(&self) -> Option<KademliaID> {
+        self.shortlist.first().map(|c| c.contact.get_distance())
+    }
+
+    fn update_progress(&mut self, alpha: usize) {
+        let current_closest = self.current_closest();
+        let improved = match (current_closest, self.closest_distance) {
+            (Some(new), Some(old)) => new.less(&old),
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+
+        if improved {
+            self.closest_distance = current_closest;
+            self.unproductive_responses = 0;
+            self.final_phase = false;
+        } else {
+            self.unproductive_responses += 1;
+            if self.unproductive_responses >= alpha && !self.final_phase {
+                println!("No closer contact found, querying the remaining k closest.");
+                self.final_phase = true;
+            }
+        }
+    }
+}
+
+pub struct Lookup<S: LookupStrategy> {
+    strategy: S,
+    own_id: KademliaID,
+    target_id: KademliaID,
+    k: usize,
+    alpha: usize,
+    stall_timeout: Duration,
+    static_puzzle_difficulty: u32,
+    dynamic_puzzle_difficulty: u32,
+    // a single path unless disjoint_paths asks for more, S/Kademlia style
+    paths: Vec<LookupPath>,
+    result: Option<S::Output>,
+    rounds: usize,
+    queried: Vec<Contact>,
+    responsive: Vec<Contact>,
+    timed_out: Vec<Contact>,
+    rejected: Vec<Contact>,
+    value_from: Option<Contact>,
+    started: Instant,
+}
+
+impl<S: LookupStrategy> Lookup<S> {
+    pub fn new(
+        strategy: S,
+        own_id: KademliaID,
+        target_id: KademliaID,
+        config: &KademliaConfig,
+        initial_contacts: Vec<Contact>,
+    ) -> Self {
+        let path_count = config.disjoint_paths.max(1);
+        let mut lookup = Self {
+            strategy,
+            own_id,
+            target_id,
+            k: config.k,
+            alpha: config.alpha,
+            stall_timeout: config.stall_timeout,
+            static_puzzle_difficulty: config.static_puzzle_difficulty,
+            dynamic_puzzle_difficulty: config.dynamic_puzzle_difficulty,
+            paths: (0..path_count).map(|_| LookupPath::new()).collect(),
+            result: None,
+            rounds: 0,
+            queried: Vec::new(),
+            responsive: Vec::new(),
+            timed_out: Vec::new(),
+            rejected: Vec::new(),
+            value_from: None,
+            started: Instant::now(),
+        };
+        // the k closest known contacts are dealt out over the paths
+        for (index, contact) in initial_contacts.into_iter().take(lookup.k).enumerate() {
+            lookup.add_candidate(index % path_count, contact, 1);
+        }
+        for path in &mut lookup.paths {
+            path.closest_distance = path.current_closest();
+        }
+        lookup
+    }
+
+    pub fn command(&self) -> Command {
+        self.strategy.command()
+    }
+
+    pub fn target_id(&self) -> KademliaID {
+        self.target_id
+    }
+
+    // contacts that should be queried now, they are marked as queried
+    pub fn next_queries(&mut self, now: Instant) -> Vec<Contact> {
+        if self.result.is_some() {
+            return Vec::new();
+        }
+
+        let mut queries = Vec::new();
+        for path in &mut self.paths {
+            for (kad_id, started) in &path.outstanding {
+                if now.duration_since(*started) >= self.stall_timeout
+                    && !path.stalled.contains(kad_id)
+                {
+                    println!(
+                        "Contact {} is stalled, querying another one in the meantime",
+                        kad_id.to_hex()
+                    );
+                    path.stalled.push(*kad_id);
+                }
+            }
+
+            // alpha queries in flight while we are making progress, every
+            // unqueried contact among the k closest once we are not
+            let window = if path.final_phase { self.k } else { self.alpha };
+            let active = path.outstanding.len() - path.stalled.len();
+            let free_slots = window.saturating_sub(active);
+
+            for candidate in path
+                .shortlist
+                .iter_mut()
+                .take(self.k)
+                .filter(|c| !c.queried)
+                .take(free_slots)
+            {
+                candidate.queried = true;
+                self.rounds = self.rounds.max(candidate.hop);
+                path.outstanding.insert(candidate.contact.id, now);
+                self.queried.push(candidate.contact.clone());
+                queries.push(candidate.contact.clone());
+            }
+        }
+        queries
+    }
+
+    // when the oldest query that still counts against the window stalls
+    pub fn next_stall_deadline(&self) -> Option<Instant> {
+        self.paths
+            .iter()
+            .flat_map(|path| {
+                path.outstanding
+                    .iter()
+                    .filter(|(kad_id, _)| !path.stalled.contains(kad_id))
+                    .map(|(_, started)| *started + self.stall_timeout)
+            })
+            .min()
+    }
+
+    pub fn on_response(&mut self, from: &Contact, response: RpcMessage) {
+        let Some(index) = self.finish_query(&from.id) else {
+            return;
+        };
+        let progress = self.strategy.interpret(from, response);
+        if let Progress::Reject = progress {
+            self.rejected.push(from.clone());
+            let path = &mut self.paths[index];
+            path.shortlist.retain(|c| c.contact.id != from.id);
+            path.update_progress(self.alpha);
+            return;
+        }
+
+        self.responsive.push(from.clone());
+        let mut hop = 1;
+        if let Some(candidate) = self.paths[index]
+            .shortlist
+            .iter_mut()
+            .find(|c| c.contact.id == from.id)
+        {
+            candidate.responded = true;
+            hop = candidate.hop;
+        }
+
+        match progress {
+            Progress::Done(output) => {
+                self.result = Some(output);
+                self.value_from = Some(from.clone());
+            }
+            Progress::Continue(contacts) => {
+                for contact in contacts {
+                    self.add_candidate(index, contact, hop + 1);
+                }
+                self.paths[index].update_progress(self.alpha);
+            }
+            Progress::Reject => {}
+        }
+    }
+
+    pub fn on_failure(&mut self, from: &Contact) {
+        let Some(index) = self.finish_query(&from.id) else {
+            return;
+        };
+        println!(
+            "No response from contact: {}. Marking as unreachable.",
+            from.id.to_hex()
+        );
+        self.timed_out.push(from.clone());
+        let path = &mut self.paths[index];
+        path.shortlist.retain(|c| c.contact.id != from.id);
+        path.update_progress(self.alpha);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.result.is_some() || self.paths.iter().all(|path| path.is_finished(self.k))
+    }
+
+    pub fn finish(self) -> LookupReport<S::Output> {
+        let mut timed_out = self.timed_out;
+        let mut candidates = Vec::new();
+        for path in self.paths {
+            // stalled peers that never answered before we gave up on them
+            for kad_id in &path.stalled {
+                if let Some(contact) = self.queried.iter().find(|c| c.id == *kad_id) {
+                    timed_out.push(contact.clone());
+                }
+            }
+            candidates.extend(path.shortlist.into_iter().filter(|c| c.responded));
+        }
+        // the paths are merged back into one list, closest first
+        candidates.sort_by_key(|c| c.contact.get_distance());
+
+        let (result, holders) = match self.result {
+            Some(output) => (output, self.value_from.into_iter().collect()),
+            None => {
+                let closest = candidates
+                    .iter()
+                    .map(|c| c.contact.clone())
+                    .take(self.k)
+                    .collect();
+                self.strategy.finish(closest)
+            }
+        };
+        let value_from = holders.first().cloned();
+        let closest_without_value = candidates
+            .into_iter()
+            .find(|c| !holders.iter().any(|holder| holder.id == c.contact.id))
+            .map(|c| c.contact);
+
+        LookupReport {
+            result,
+            rounds: self.rounds,
+            queried: self.queried,
+            responsive: self.responsive,
+            timed_out,
+            rejected: self.rejected,
+            value_from,
+            closest_without_value,
+            elapsed: self.started.elapsed(),
+        }
+    }
+
+    // the path that was waiting for this contact
+    fn finish_query(&mut self, kad_id: &KademliaID) -> Option<usize> {
+        self.paths
+            .iter_mut()
+            .position(|path| path.finish_query(kad_id))
+    }
+
+    fn add_candidate(&mut self, index: usize, mut contact: Contact, hop: usize) {
+        // a contact that was already asked, or belongs to another path, is
+        // not taken again so the paths stay disjoint
+        if contact.id == self.own_id
+            || self.queried.iter().any(|c| c.id == contact.id)
+            || self.paths.iter().any(|path| path.contains(&contact.id))
+        {
+            return;
+        }
+        // peers could hand out ids that would never make it into a routing table
+        if !puzzle::verify_contact(
+            &contact,
+            self.static_puzzle_difficulty,
+            self.dynamic_puzzle_difficulty,
+        ) {
+            return;
+        }
+        contact.calc_distance(&self.target_id);
+        self.paths[index].add_candidate(contact, hop);
+    }
+}
@@ -1,34 +1,150 @@
 use {
-    axum::{routing::get, Router},
-    kadrustlia::{cli::Cli, constants::ALL_IPV4, kademlia::Kademlia},
-    std::sync::Arc,
+    axum::{
+        extract::{Path, State},
+        http::StatusCode,
+        routing::{delete, get},
+        Router,
+    },
+    kadrustlia::{
+        cli::Cli,
+        config::Config,
+        constants::{ALL_INTERFACES, ROUTING_TABLE_SAVE_INTERVAL_SECS},
+        kademlia::Kademlia,
+        kademlia_id::KademliaID,
+        node_state, puzzle,
+    },
+    std::{
+        io::ErrorKind,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    },
 };
 
 async fn root() -> &'static str {
     "Hello world!"
 }
 
+async fn delete_object(
+    State(kademlia): State<Arc<Kademlia>>,
+    Path(hash): Path<String>,
+) -> (StatusCode, String) {
+    let Some(target_id) = KademliaID::try_from_hex(&hash) else {
+        return (StatusCode::BAD_REQUEST, format!("invalid key '{}'", hash));
+    };
+    match kademlia.iterative_delete(target_id).await {
+        Ok(report) => (
+            StatusCode::OK,
+            format!(
+                "deleted from {} nodes, {} did not answer",
+                report.deleted.len(),
+                report.failed.len()
+            ),
+        ),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => (StatusCode::FORBIDDEN, e.to_string()),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+// resolves on Ctrl-C or, on unix, SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            eprintln!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                eprintln!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let bind_addr = config.bind_addr();
+    let state_dir = config.state_dir.clone();
+
+    let node_key = match config.node_key() {
+        Some(node_key) => node_key,
+        None => {
+            node_state::load_or_create_node_key(
+                &state_dir,
+                config.regenerate_id,
+                config.static_puzzle_difficulty,
+            )
+            .await?
+        }
+    };
+    let own_id = KademliaID::from_public_key(&node_key.verifying_key());
+    if !puzzle::solves_static(&own_id, config.static_puzzle_difficulty) {
+        eprintln!("node key does not solve the static id puzzle, use --regenerate-id");
+        std::process::exit(2);
+    }
+
+    let kademlia = Arc::new(Kademlia::with_config(node_key, config.kademlia_config()));
+    kademlia.bind(bind_addr).await?;
+
     // REST interface
+    let http_addr = config.http_addr();
+    let kademlia_http = Arc::clone(&kademlia);
     tokio::spawn(async move {
-        let app = Router::new().route("/", get(root));
-        let ip = format!("{}:{}", ALL_IPV4, "3000");
-        let listener = tokio::net::TcpListener::bind(ip).await.unwrap();
-        axum::serve(listener, app).await.unwrap();
+        let app = Router::new()
+            .route("/", get(root))
+            .route("/objects/:hash", delete(delete_object))
+            .with_state(kademlia_http);
+        let listener = match tokio::net::TcpListener::bind(http_addr).await {
+            // hosts without ipv6 fall back to all ipv4 interfaces, like the rpc socket
+            Err(e) if http_addr.ip() == ALL_INTERFACES => {
+                println!("Cannot bind {} ({}), using IPv4 only", http_addr, e);
+                let ipv4_addr =
+                    SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), http_addr.port());
+                tokio::net::TcpListener::bind(ipv4_addr).await
+            }
+            listener => listener,
+        };
+        let listener = match listener {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Failed to start the REST interface on {}: {}", http_addr, e);
+                return;
+            }
+        };
+        if let Err(e) = axum::serve(listener, app).await {
+            eprintln!("REST interface stopped: {}", e);
+        }
     });
 
-    let bind_addr = format!("{}:{}", ALL_IPV4, "5678");
-
-    let kademlia = Arc::new(Kademlia::new());
-
     let (shutdown_tx, _) = tokio::sync::broadcast::channel(1);
 
     let kademlia_listen = Arc::clone(&kademlia);
     let mut shutdown_rx = shutdown_tx.subscribe();
     let listen_task = tokio::spawn(async move {
         tokio::select! {
-            _ = kademlia_listen.listen(&bind_addr) => {},
+            _ = kademlia_listen.listen(bind_addr) => {},
             _ = shutdown_rx.recv() => {
                 println!("shutting down listen task...");
             },
@@ -36,18 +152,45 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     });
 
     let kademlia_join = Arc::clone(&kademlia);
+    let join_state_dir = state_dir.clone();
     let join_task = tokio::spawn(async move {
-        if let Err(e) = kademlia_join.join().await {
+        if let Err(e) = kademlia_join.join_from_state(&join_state_dir).await {
             eprintln!("Error during join: {}", e);
         }
     });
 
+    let kademlia_save = Arc::clone(&kademlia);
+    let save_state_dir = state_dir.clone();
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(ROUTING_TABLE_SAVE_INTERVAL_SECS));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = kademlia_save.save_routing_table(&save_state_dir).await {
+                eprintln!("Failed to save routing table: {}", e);
+            }
+        }
+    });
+
     let kademlia_cli = Arc::clone(&kademlia);
     let cli = Cli::new(kademlia_cli, shutdown_tx.clone());
     let cli_task = tokio::spawn(async move {
         cli.read_input().await;
     });
 
-    let _ = tokio::join!(listen_task, join_task, cli_task);
-    Ok(())
+    // docker stop and Ctrl-C end the node like the exit command does
+    tokio::select! {
+        _ = async { tokio::join!(listen_task, join_task, cli_task) } => {},
+        _ = shutdown_signal() => {
+            println!("shutting down...");
+            let _ = shutdown_tx.send(());
+        },
+    }
+
+    if let Err(e) = kademlia.save_routing_table(&state_dir).await {
+        eprintln!("Failed to save routing table: {}", e);
+    }
+    // a pending stdin read sits on a blocking thread the runtime would wait for
+    std::process::exit(0)
 }
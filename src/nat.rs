@@ -0,0 +1,61 @@
+use {
+    crate::{constants::ADDRESS_VOTES, limits::subnet},
+    std::{
+        collections::HashMap,
+        net::{IpAddr, SocketAddr},
+    },
+};
+
+// above this many votes the old ones are thrown away
+const MAX_VOTES: usize = 64;
+
+// learns our external address from the source address peers saw on our
+// PINGs. only contacts already in the routing table vote and every /24 or
+// /64 has a single vote, so a few colluding peers can not move it
+pub struct AddressDiscovery {
+    advertised: SocketAddr,
+    enabled: bool,
+    votes: HashMap<IpAddr, SocketAddr>,
+}
+
+impl AddressDiscovery {
+    pub fn new(advertised: SocketAddr, enabled: bool) -> Self {
+        Self {
+            advertised,
+            enabled,
+            votes: HashMap::new(),
+        }
+    }
+
+    pub fn advertised(&self) -> SocketAddr {
+        self.advertised
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // the new address once peers in ADDRESS_VOTES subnets saw us at the same one
+    pub fn observe(&mut self, peer: IpAddr, observed: SocketAddr) -> Option<SocketAddr> {
+        if !self.enabled {
+            return None;
+        }
+        let voter = subnet(peer);
+        if self.votes.len() >= MAX_VOTES && !self.votes.contains_key(&voter) {
+            self.votes.clear();
+        }
+        self.votes.insert(voter, observed);
+
+        let agreeing = self
+            .votes
+            .values()
+            .filter(|addr| **addr == observed)
+            .count();
+        if observed == self.advertised || agreeing < ADDRESS_VOTES {
+            return None;
+        }
+        self.advertised = observed;
+        self.votes.clear();
+        Some(observed)
+    }
+}
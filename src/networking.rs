@@ -1,69 +1,502 @@
-use tokio::{fs, sync::oneshot};
+use tokio::sync::oneshot;
 use {
     crate::{
-        constants::rpc::Command, contact::Contact, kademlia_id::KademliaID,
-        routing_table_handler::*, rpc::RpcMessage,
+        config::KademliaConfig,
+        constants::{
+            rpc::Command, ALL_INTERFACES, HANDSHAKE_BURST, HANDSHAKE_RATE, MAX_DATAGRAM_SIZE,
+            MAX_FRAME_SIZE, MAX_SEEN_REQUESTS, PING_BACK_BURST, PING_BACK_RATE, REPLAY_WINDOW_SECS,
+        },
+        contact::Contact,
+        kademlia_id::KademliaID,
+        limits::{RateLimiter, StoreQuota},
+        nat::AddressDiscovery,
+        puzzle,
+        routing_table_handler::*,
+        rpc::{Envelope, RpcMessage, RpcSender, SeenRequests, SignedRpc},
+        session::{Handshake, Packet, Sessions},
+        storage, utils,
+    },
+    ed25519_dalek::SigningKey,
+    socket2::{Domain, Socket, Type},
+    std::{
+        collections::{HashMap, HashSet},
+        io::ErrorKind,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
     },
-    std::{collections::HashMap, sync::Arc, time::Duration},
     tokio::{
-        net::{lookup_host, UdpSocket},
-        sync::{mpsc, Mutex},
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{lookup_host, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+        sync::{mpsc, OnceCell, OwnedSemaphorePermit, Semaphore},
     },
 };
 
-type RpcMap = Arc<Mutex<HashMap<KademliaID, oneshot::Sender<RpcMessage>>>>;
+type RpcMap = Arc<Mutex<HashMap<KademliaID, PendingRequest>>>;
+
+const MAX_DATAGRAM_ONLY_PEERS: usize = 10_000;
+// advertised addresses remembered as checked before the list starts over
+const MAX_PING_BACKS: usize = 10_000;
+// what sealing adds to a signed message, the session id, nonce and tag
+const SEALING_OVERHEAD: usize = 64;
+
+// a request we sent, only a response from the address we sent it to and
+// for the same command is accepted
+struct PendingRequest {
+    reply_tx: oneshot::Sender<RpcMessage>,
+    addr: SocketAddr,
+    command: Command,
+}
+
+// removes the response_map entry of a request once its caller stops
+// waiting, also when a lookup drops a request that is still in flight
+struct PendingRpc {
+    response_map: RpcMap,
+    rpc_id: KademliaID,
+}
+
+impl Drop for PendingRpc {
+    fn drop(&mut self) {
+        if let Ok(mut map) = self.response_map.lock() {
+            map.remove(&self.rpc_id);
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Networking {
     response_map: RpcMap,
+    socket: Arc<OnceCell<Arc<UdpSocket>>>,
+    stream_listener: Arc<Mutex<Option<TcpListener>>>,
+    accepts_streams: Arc<AtomicBool>,
+    datagram_only: Arc<Mutex<HashSet<SocketAddr>>>,
+    route_table_tx: Arc<OnceCell<mpsc::Sender<RouteTableCMD>>>,
+    storage_dir: PathBuf,
+    rpc_timeout: Duration,
+    signing_key: Arc<SigningKey>,
+    own_id: KademliaID,
+    nonce: u64,
+    seen_requests: Arc<Mutex<SeenRequests>>,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    handshake_limiter: Arc<Mutex<RateLimiter>>,
+    ping_back_limiter: Arc<Mutex<RateLimiter>>,
+    ping_backs: Arc<Mutex<HashMap<KademliaID, SocketAddr>>>,
+    handler_permits: Arc<Semaphore>,
+    max_store_size: usize,
+    store_quota: Arc<Mutex<StoreQuota>>,
+    unmatched_responses: Arc<AtomicUsize>,
+    sessions: Arc<Mutex<Sessions>>,
+    address_discovery: Arc<Mutex<AddressDiscovery>>,
+    encrypt: bool,
+    prefer_tcp: bool,
+    client: bool,
+}
+impl Default for Networking {
+    fn default() -> Self {
+        Self::new()
+    }
 }
+
 impl Networking {
     pub fn new() -> Self {
+        Self::with_config(
+            &KademliaConfig::default(),
+            SigningKey::generate(&mut rand::rngs::OsRng),
+        )
+    }
+
+    // every message we send is signed with signing_key, our node id is the
+    // hash of its public key
+    pub fn with_config(config: &KademliaConfig, signing_key: SigningKey) -> Self {
+        let kad_id = KademliaID::from_public_key(&signing_key.verifying_key());
+        let nonce = puzzle::solve_dynamic(&kad_id, config.dynamic_puzzle_difficulty);
         Self {
             response_map: Arc::new(Mutex::new(HashMap::new())),
+            socket: Arc::new(OnceCell::new()),
+            stream_listener: Arc::new(Mutex::new(None)),
+            accepts_streams: Arc::new(AtomicBool::new(false)),
+            datagram_only: Arc::new(Mutex::new(HashSet::new())),
+            route_table_tx: Arc::new(OnceCell::new()),
+            storage_dir: config.storage_dir.clone(),
+            rpc_timeout: config.rpc_timeout,
+            signing_key: Arc::new(signing_key),
+            own_id: kad_id,
+            nonce,
+            seen_requests: Arc::new(Mutex::new(SeenRequests::new(
+                REPLAY_WINDOW_SECS,
+                MAX_SEEN_REQUESTS,
+            ))),
+            rate_limiter: Arc::new(Mutex::new(RateLimiter::new(
+                config.rate_limit,
+                config.rate_burst,
+            ))),
+            handshake_limiter: Arc::new(Mutex::new(RateLimiter::new(
+                HANDSHAKE_RATE,
+                HANDSHAKE_BURST,
+            ))),
+            ping_back_limiter: Arc::new(Mutex::new(RateLimiter::new(
+                PING_BACK_RATE,
+                PING_BACK_BURST,
+            ))),
+            ping_backs: Arc::new(Mutex::new(HashMap::new())),
+            handler_permits: Arc::new(Semaphore::new(config.max_concurrent_handlers)),
+            max_store_size: config.max_store_size,
+            store_quota: Arc::new(Mutex::new(StoreQuota::new(
+                config.max_stores_per_peer,
+                config.max_stores_per_subnet,
+            ))),
+            unmatched_responses: Arc::new(AtomicUsize::new(0)),
+            sessions: Arc::new(Mutex::new(Sessions::default())),
+            address_discovery: Arc::new(Mutex::new(AddressDiscovery::new(
+                config.advertise_address,
+                config.discover_address,
+            ))),
+            encrypt: config.encrypt,
+            prefer_tcp: config.prefer_tcp,
+            client: config.client,
+        }
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    // responses and errors dropped because they did not match a request we sent
+    pub fn unmatched_responses(&self) -> usize {
+        self.unmatched_responses.load(Ordering::Relaxed)
+    }
+
+    // where other nodes should reach us, learned from PONGs unless it was configured
+    pub fn advertise_address(&self) -> SocketAddr {
+        self.address_discovery.lock().unwrap().advertised()
+    }
+
+    // whether we listen for streams next to the udp socket, every message
+    // we sign tells peers about it
+    pub fn accepts_streams(&self) -> bool {
+        self.accepts_streams.load(Ordering::Relaxed)
+    }
+
+    // sealed with the session we have with addr, only signed when there is none
+    fn encode(
+        &self,
+        addr: Option<&SocketAddr>,
+        recipient: Option<KademliaID>,
+        message: &RpcMessage,
+    ) -> Vec<u8> {
+        // a client has no listener, peers must never try to stream to it
+        debug_assert!(!(self.client && self.accepts_streams()));
+        let envelope = Envelope {
+            nonce: self.nonce,
+            tcp: self.accepts_streams() && !self.client,
+            client: self.client,
+            recipient,
+            timestamp: utils::unix_time(),
+        };
+        let signed = SignedRpc::seal(&self.signing_key, envelope, message);
+        let sealed = addr.and_then(|addr| {
+            let plaintext = bincode::serialize(&signed).expect("failed to serialize data");
+            self.sessions
+                .lock()
+                .unwrap()
+                .seal(addr, recipient, &plaintext)
+        });
+        bincode::serialize(&sealed.unwrap_or(Packet::Plain(signed)))
+            .expect("failed to serialize data")
+    }
+
+    // the verified sender and message of a plain or sealed packet and
+    // whether it came encrypted
+    fn open_packet(
+        &self,
+        packet: Packet,
+        src: SocketAddr,
+    ) -> Option<(RpcSender, RpcMessage, bool)> {
+        let (signed, session_peer) = match packet {
+            Packet::Plain(signed) => (signed, None),
+            Packet::Sealed {
+                session_id,
+                nonce,
+                ciphertext,
+            } => {
+                let opened =
+                    self.sessions
+                        .lock()
+                        .unwrap()
+                        .open(src, &session_id, &nonce, &ciphertext);
+                match opened.and_then(|(plaintext, peer_id)| {
+                    Some((
+                        bincode::deserialize::<SignedRpc>(&plaintext).ok()?,
+                        Some(peer_id),
+                    ))
+                }) {
+                    Some(opened) => opened,
+                    None => {
+                        println!("Dropping message from {} that does not decrypt", src);
+                        return None;
+                    }
+                }
+            }
+            Packet::Handshake(_) => return None,
+        };
+
+        // anything that is not signed by the key its sender id derives from is
+        // dropped, inside a session only the peer it was set up with may talk
+        let Some((sender, message)) = signed
+            .open()
+            .filter(|(sender, _)| session_peer.is_none_or(|peer_id| peer_id == sender.id))
+        else {
+            println!("Dropping unsigned message from {}", src);
+            return None;
+        };
+        // a signed message still must not be replayed to another node or long after
+        if !self
+            .seen_requests
+            .lock()
+            .unwrap()
+            .is_fresh(sender.timestamp, utils::unix_time())
+        {
+            println!("Dropping stale message from {}", src);
+            return None;
+        }
+        if sender
+            .recipient
+            .is_some_and(|recipient| recipient != self.own_id)
+        {
+            println!("Dropping message from {} meant for another node", src);
+            return None;
         }
+        Some((sender, message, session_peer.is_some()))
     }
 
+    // sets up an encrypted session with recipient at addr unless there
+    // already is one, a session with whoever answers when it is None
+    async fn ensure_session(
+        &self,
+        addr: SocketAddr,
+        recipient: Option<KademliaID>,
+    ) -> std::io::Result<()> {
+        if self.sessions.lock().unwrap().has_session(&addr, recipient) {
+            return Ok(());
+        }
+        let (handshake, done) =
+            self.sessions
+                .lock()
+                .unwrap()
+                .begin(&self.signing_key, addr, recipient);
+        let session_id = handshake.session_id;
+        let bin_data =
+            bincode::serialize(&Packet::Handshake(handshake)).expect("failed to serialize data");
+        self.send_to(&bin_data, addr).await?;
+
+        match tokio::time::timeout(self.rpc_timeout, done).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) | Err(_) => {
+                self.sessions.lock().unwrap().abandon(&session_id);
+                Err(no_session(addr))
+            }
+        }
+    }
+
+    // the reply to a handshake from src, if it needs one
+    fn accept_handshake(&self, handshake: Handshake, src: SocketAddr) -> Option<Handshake> {
+        // every new handshake costs a key exchange and a session, so it counts
+        // as a request and against a tighter budget before anything is kept
+        let now = Instant::now();
+        let allowed = || {
+            self.handshake_limiter.lock().unwrap().allow(src.ip(), now)
+                && self.rate_limiter.lock().unwrap().allow(src.ip(), now)
+        };
+        if !handshake.reply && !allowed() {
+            println!("Rate limiting handshake from {}", src);
+            return None;
+        }
+        self.sessions
+            .lock()
+            .unwrap()
+            .accept(&self.signing_key, &handshake, src)
+    }
+
+    // binds the socket used both for listening and for sending, so that
+    // peers can answer to the source address of our requests, and a tcp
+    // listener on the same port for messages that do not fit in a datagram,
+    // clients answer nothing and only get the socket
+    pub async fn bind(&self, bind_addr: SocketAddr) -> std::io::Result<SocketAddr> {
+        let socket = self
+            .socket
+            .get_or_try_init(|| async {
+                let socket = match bind_socket(bind_addr, Type::DGRAM) {
+                    // hosts without ipv6 fall back to all ipv4 interfaces
+                    Err(e) if bind_addr.ip() == ALL_INTERFACES => {
+                        println!("Cannot bind {} ({}), using IPv4 only", bind_addr, e);
+                        let ipv4_addr =
+                            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), bind_addr.port());
+                        bind_socket(ipv4_addr, Type::DGRAM)?
+                    }
+                    socket => socket?,
+                };
+                UdpSocket::from_std(socket.into()).map(Arc::new)
+            })
+            .await?;
+        let local_addr = socket.local_addr()?;
+
+        if !self.client && !self.accepts_streams() {
+            let listener = bind_socket(local_addr, Type::STREAM).and_then(|socket| {
+                socket.listen(1024)?;
+                TcpListener::from_std(socket.into())
+            });
+            match listener {
+                Ok(listener) => {
+                    *self.stream_listener.lock().unwrap() = Some(listener);
+                    self.accepts_streams.store(true, Ordering::Relaxed);
+                }
+                Err(e) => println!("Not accepting streams on {}: {}", local_addr, e),
+            }
+        }
+        Ok(local_addr)
+    }
+
+    // the bound socket, or a fresh one of the family of addr before we listen
+    async fn outgoing_socket(&self, addr: SocketAddr) -> std::io::Result<Arc<UdpSocket>> {
+        match self.socket.get() {
+            Some(socket) => Ok(Arc::clone(socket)),
+            None => {
+                let unspecified = match addr {
+                    SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    SocketAddr::V6(_) => ALL_INTERFACES,
+                };
+                Ok(Arc::new(UdpSocket::bind((unspecified, 0)).await?))
+            }
+        }
+    }
+
+    // a dual-stack socket reaches ipv4 peers under their v4-mapped address
+    async fn send_to(&self, bin_data: &[u8], addr: SocketAddr) -> std::io::Result<()> {
+        let socket = self.outgoing_socket(addr).await?;
+        let target = match (socket.local_addr()?, addr) {
+            (SocketAddr::V6(_), SocketAddr::V4(v4)) => {
+                SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+            }
+            _ => addr,
+        };
+        socket.send_to(bin_data, target).await?;
+        Ok(())
+    }
+
+    // for peers whose id we do not know yet, like boot nodes, the request
+    // is not addressed and peers only answer it if it changes nothing
     pub async fn send_rpc_request_await(
         &self,
         rpc_id: KademliaID,
-        target_addr: &str,
+        target_addr: impl ToSocketAddrs,
         cmd: Command,
         target_id: Option<KademliaID>,
         data: Option<String>,
         contact: Option<Vec<Contact>>,
     ) -> std::io::Result<Option<RpcMessage>> {
-        let (tx, rx) = oneshot::channel();
+        let Some(addr) = resolve(target_addr).await? else {
+            return Ok(None);
+        };
+        let request = RpcMessage::Request {
+            rpc_id,
+            method: cmd,
+            target_id,
+            data,
+            contact,
+        };
+        self.request(addr, None, request).await
+    }
 
-        {
-            let mut map = self.response_map.lock().await;
-            map.insert(rpc_id.clone(), tx);
+    // the request is signed for recipient only, so it cannot be replayed
+    // to any other node
+    pub async fn send_rpc_request_to_contact(
+        &self,
+        rpc_id: KademliaID,
+        recipient: &Contact,
+        cmd: Command,
+        target_id: Option<KademliaID>,
+        data: Option<String>,
+        contact: Option<Vec<Contact>>,
+    ) -> std::io::Result<Option<RpcMessage>> {
+        let request = RpcMessage::Request {
+            rpc_id,
+            method: cmd,
+            target_id,
+            data,
+            contact,
+        };
+        self.request(recipient.address, Some(recipient.id), request)
+            .await
+    }
+
+    // requests that do not fit in a datagram, and all of them with
+    // prefer_tcp, go over a stream, unless the peer told us it has none
+    async fn request(
+        &self,
+        addr: SocketAddr,
+        recipient: Option<KademliaID>,
+        request: RpcMessage,
+    ) -> std::io::Result<Option<RpcMessage>> {
+        let RpcMessage::Request {
+            rpc_id,
+            method: cmd,
+            ..
+        } = request
+        else {
+            return Ok(None);
+        };
+
+        let too_large =
+            self.encode(None, recipient, &request).len() + SEALING_OVERHEAD > MAX_DATAGRAM_SIZE;
+        let datagram_only = self.datagram_only.lock().unwrap().contains(&addr);
+        if too_large && datagram_only {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{:?} is too large for {}, which takes no streams",
+                    cmd, addr
+                ),
+            ));
         }
+        let mut over_stream = too_large || (self.prefer_tcp && !datagram_only);
 
-        self.send_rpc_request(rpc_id.clone(), target_addr, cmd, target_id, data, contact)
-            .await?;
+        loop {
+            let (tx, rx) = oneshot::channel();
+            self.response_map.lock().unwrap().insert(
+                rpc_id,
+                PendingRequest {
+                    reply_tx: tx,
+                    addr,
+                    command: cmd,
+                },
+            );
+            let _pending = PendingRpc {
+                response_map: Arc::clone(&self.response_map),
+                rpc_id,
+            };
 
-        let timeout_duration = Duration::from_secs(15);
-        match tokio::time::timeout(timeout_duration, rx).await {
-            Ok(Ok(response)) => {
-                {
-                    let mut map = self.response_map.lock().await;
-                    map.remove(&rpc_id);
+            if over_stream {
+                if let Err(e) = self.request_over_stream(addr, recipient, &request).await {
+                    println!("{:?} over a stream to {} failed: {}", cmd, addr, e);
+                    return Ok(None);
                 }
-                Ok(Some(response))
-            }
-            Ok(Err(_)) => {
-                {
-                    let mut map = self.response_map.lock().await;
-                    map.remove(&rpc_id);
-                }
-                Ok(None)
+            } else {
+                self.send_request_to(addr, recipient, cmd, &request).await?;
             }
-            Err(_) => {
-                {
-                    let mut map = self.response_map.lock().await;
-                    map.remove(&rpc_id);
+
+            match tokio::time::timeout(self.rpc_timeout, rx).await {
+                // the answer did not fit in a datagram, ask again over a stream
+                Ok(Ok(RpcMessage::UseStream { .. })) if !over_stream => over_stream = true,
+                Ok(Ok(response)) => return Ok(Some(response)),
+                Ok(Err(_)) | Err(_) => {
+                    // the peer may have restarted and lost our session
+                    self.sessions.lock().unwrap().forget(&addr);
+                    return Ok(None);
                 }
-                Ok(None)
             }
         }
     }
@@ -71,362 +504,834 @@ impl Networking {
     pub async fn send_rpc_request(
         &self,
         rpc_id: KademliaID,
-        target_addr: &str,
+        target_addr: impl ToSocketAddrs,
         cmd: Command,
         target_id: Option<KademliaID>,
         data: Option<String>,
         contact: Option<Vec<Contact>>,
     ) -> std::io::Result<()> {
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        let rpc_msg = RpcMessage::Request {
-            rpc_id,
-            method: cmd,
-            target_id,
-            data,
-            contact,
+        if let Some(addr) = resolve(target_addr).await? {
+            let request = RpcMessage::Request {
+                rpc_id,
+                method: cmd,
+                target_id,
+                data,
+                contact,
+            };
+            self.send_request_to(addr, None, cmd, &request).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_request_to(
+        &self,
+        addr: SocketAddr,
+        recipient: Option<KademliaID>,
+        cmd: Command,
+        request: &RpcMessage,
+    ) -> std::io::Result<()> {
+        if self.encrypt {
+            self.ensure_session(addr, recipient).await?;
+        }
+        let bin_data = self.encode(Some(&addr), recipient, request);
+        self.send_to(&bin_data, addr).await?;
+        println!("Sent {:?} to {}", cmd, &addr);
+        Ok(())
+    }
+
+    // one request and its answer over a fresh connection, the answer is
+    // handed to the waiting caller just like one that came as a datagram
+    async fn request_over_stream(
+        &self,
+        addr: SocketAddr,
+        recipient: Option<KademliaID>,
+        request: &RpcMessage,
+    ) -> std::io::Result<()> {
+        let exchange = async {
+            let mut stream = TcpStream::connect(addr).await?;
+            if self.encrypt && !self.sessions.lock().unwrap().has_session(&addr, recipient) {
+                let (handshake, _done) =
+                    self.sessions
+                        .lock()
+                        .unwrap()
+                        .begin(&self.signing_key, addr, recipient);
+                let session_id = handshake.session_id;
+                let bin_data = bincode::serialize(&Packet::Handshake(handshake))
+                    .expect("failed to serialize data");
+                write_frame(&mut stream, &bin_data).await?;
+                if let Ok(Packet::Handshake(reply)) =
+                    bincode::deserialize(&read_frame(&mut stream).await?)
+                {
+                    self.sessions
+                        .lock()
+                        .unwrap()
+                        .accept(&self.signing_key, &reply, addr);
+                }
+                if !self.sessions.lock().unwrap().has_session(&addr, recipient) {
+                    self.sessions.lock().unwrap().abandon(&session_id);
+                    return Err(no_session(addr));
+                }
+            }
+            write_frame(&mut stream, &self.encode(Some(&addr), recipient, request)).await?;
+            read_frame(&mut stream).await
         };
-        for addr in lookup_host(target_addr).await? {
-            let bin_data = bincode::serialize(&rpc_msg).expect("failed to serialize data");
-            socket.send_to(&bin_data, &addr).await?;
-            println!("Sent {:?} to {}", cmd, &addr);
-            break;
+        let frame = tokio::time::timeout(self.rpc_timeout, exchange)
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::TimedOut, "no answer in time"))??;
+
+        let opened = bincode::deserialize(&frame)
+            .ok()
+            .and_then(|packet| self.open_packet(packet, addr))
+            .filter(|(_, _, encrypted)| *encrypted || !self.encrypt);
+        let answered = match opened {
+            Some((sender, message, _)) => self.handle_reply(addr, sender, message).await,
+            None => false,
+        };
+        if !answered {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "answer does not match the request",
+            ));
         }
         Ok(())
     }
 
+    // the waiting caller of a request this answers, errors can answer any command
+    fn take_pending(
+        &self,
+        rpc_id: &KademliaID,
+        src: SocketAddr,
+        result: Option<Command>,
+    ) -> Option<oneshot::Sender<RpcMessage>> {
+        let mut response_map = self.response_map.lock().unwrap();
+        let matches = response_map.get(rpc_id).is_some_and(|pending| {
+            pending.addr == src
+                && result.is_none_or(|result| result == expected_result(pending.command))
+        });
+        if !matches {
+            self.unmatched_responses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        response_map.remove(rpc_id).map(|pending| pending.reply_tx)
+    }
+
+    // peers that signed a datagram saying they take no streams
+    fn note_transports(&self, src: SocketAddr, sender: &RpcSender) {
+        let mut datagram_only = self.datagram_only.lock().unwrap();
+        if sender.tcp && !sender.client {
+            datagram_only.remove(&src);
+        } else {
+            if datagram_only.len() >= MAX_DATAGRAM_ONLY_PEERS {
+                datagram_only.clear();
+            }
+            datagram_only.insert(src);
+        }
+    }
+
     pub async fn send_rpc_response(
+        &self,
         rpc_id: KademliaID,
-        target_addr: &str,
+        target_addr: impl ToSocketAddrs,
         cmd: Command,
         data: Option<String>,
         contact: Option<Vec<Contact>>,
     ) -> tokio::io::Result<()> {
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
         let rpc_msg = RpcMessage::Response {
             rpc_id,
             result: cmd,
             data,
             contact,
         };
-        let bin_data = bincode::serialize(&rpc_msg).expect("Failed to serialize response");
+        match resolve(target_addr).await? {
+            Some(addr) => self.send_datagram(addr, None, &rpc_msg).await,
+            None => Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "address does not resolve",
+            )),
+        }
+    }
 
-        let target = if target_addr.contains(':') {
-            let parts: Vec<&str> = target_addr.split(':').collect();
-            format!("{}:5678", parts[0])
-        } else {
-            format!("{}:5678", target_addr)
-        };
+    pub async fn send_rpc_error(
+        &self,
+        rpc_id: KademliaID,
+        target_addr: impl ToSocketAddrs,
+        message: String,
+    ) -> std::io::Result<()> {
+        let rpc_msg = RpcMessage::Error { rpc_id, message };
+        match resolve(target_addr).await? {
+            Some(addr) => self.send_datagram(addr, None, &rpc_msg).await,
+            None => Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "address does not resolve",
+            )),
+        }
+    }
 
-        println!("Sending response to {}", target);
+    // a response that does not fit is replaced by UseStream, so the
+    // requester asks again over a stream, with the same rpc id
+    async fn send_datagram(
+        &self,
+        addr: SocketAddr,
+        recipient: Option<KademliaID>,
+        message: &RpcMessage,
+    ) -> std::io::Result<()> {
+        let mut bin_data = self.encode(Some(&addr), recipient, message);
+        if let RpcMessage::Response { rpc_id, .. } = message {
+            if bin_data.len() > MAX_DATAGRAM_SIZE {
+                println!("Response to {} is too large, asking for a stream", addr);
+                if let Some(recipient) = recipient {
+                    self.seen_requests
+                        .lock()
+                        .unwrap()
+                        .forget(recipient, *rpc_id);
+                }
+                bin_data = self.encode(
+                    Some(&addr),
+                    recipient,
+                    &RpcMessage::UseStream { rpc_id: *rpc_id },
+                );
+            }
+        }
 
         let mut attempts = 0;
-        while attempts < 3 {
-            if let Err(_) = socket.send_to(&bin_data, &target).await {
-                attempts += 1;
-            } else {
-                println!("Successfully sent on attempt {}", attempts + 1);
-                break;
+        loop {
+            match self.send_to(&bin_data, addr).await {
+                Ok(_) => return Ok(()),
+                Err(e) if attempts >= 2 => return Err(e),
+                Err(_) => attempts += 1,
             }
         }
-
-        Ok(())
     }
 
     pub async fn listen_for_rpc(
         &self,
         tx: mpsc::Sender<RouteTableCMD>,
-        bind_addr: &str,
+        bind_addr: SocketAddr,
     ) -> std::io::Result<()> {
-        let socket = UdpSocket::bind(bind_addr).await?;
+        let socket = self.outgoing_socket(self.bind(bind_addr).await?).await?;
+        let _ = self.route_table_tx.set(tx.clone());
         // println!("Listening for RPC messages on {}", bind_addr);
 
-        let mut buf = [0u8; 65507];
+        let listener = self.stream_listener.lock().unwrap().take();
+        if let Some(listener) = listener {
+            let networking = self.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move { networking.accept_streams(listener, tx).await });
+        }
+
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
 
         loop {
             let (len, src) = socket.recv_from(&mut buf).await?;
+            let src = canonical(src);
 
-            let received_msg: RpcMessage =
-                bincode::deserialize(&buf[..len]).expect("failed to deserialize data");
+            let packet = match bincode::deserialize::<Packet>(&buf[..len]) {
+                Ok(Packet::Handshake(handshake)) => {
+                    if let Some(reply) = self.accept_handshake(handshake, src) {
+                        let bin_data = bincode::serialize(&Packet::Handshake(reply))
+                            .expect("failed to serialize data");
+                        let _ = self.send_to(&bin_data, src).await;
+                    }
+                    continue;
+                }
+                Ok(packet) => packet,
+                Err(_) => {
+                    println!("Dropping malformed message from {}", src);
+                    continue;
+                }
+            };
+            let Some((sender, received_msg, encrypted)) = self.open_packet(packet, src) else {
+                continue;
+            };
+            self.note_transports(src, &sender);
+
+            if !matches!(received_msg, RpcMessage::Request { .. }) {
+                // a PONG echoes the address the peer saw our PING come from,
+                // only datagrams show the port our socket is mapped to
+                let observed = match &received_msg {
+                    RpcMessage::Response {
+                        result: Command::PONG,
+                        data: Some(observed),
+                        ..
+                    } => observed.parse::<SocketAddr>().ok(),
+                    _ => None,
+                };
+                // only contacts we knew before this reply get a vote
+                let voter = match observed {
+                    Some(_) if self.address_discovery.lock().unwrap().enabled() => {
+                        self.knows(Contact::new(sender.id, src)).await
+                    }
+                    _ => false,
+                };
+                if self.handle_reply(src, sender, received_msg).await && voter {
+                    if let Some(observed) = observed {
+                        self.observe_address(src, observed);
+                    }
+                }
+                continue;
+            }
+            let requester = Some(sender.id);
+            match self.admit(src, &sender, &received_msg, encrypted) {
+                Ok(permit) => {
+                    let networking = self.clone();
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        let reply = networking
+                            .handle_request(tx, src, sender, received_msg)
+                            .await;
+                        drop(permit);
+                        if let Some(reply) = reply {
+                            if let Err(e) = networking.send_datagram(src, requester, &reply).await {
+                                println!("Failed to answer {}: {}", src, e);
+                            }
+                        }
+                    });
+                }
+                Err(Some(refusal)) => {
+                    let _ = self.send_datagram(src, requester, &refusal).await;
+                }
+                Err(None) => {}
+            }
+        }
+    }
+
+    async fn accept_streams(&self, listener: TcpListener, tx: mpsc::Sender<RouteTableCMD>) {
+        loop {
+            let Ok((stream, src)) = listener.accept().await else {
+                continue;
+            };
+            let src = canonical(src);
+            let networking = self.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move { networking.serve_stream(stream, src, tx).await });
+        }
+    }
+
+    // answers handshakes and requests on one connection until the peer
+    // closes it or stays silent for an rpc timeout
+    async fn serve_stream(
+        &self,
+        mut stream: TcpStream,
+        src: SocketAddr,
+        tx: mpsc::Sender<RouteTableCMD>,
+    ) {
+        while let Ok(Ok(frame)) =
+            tokio::time::timeout(self.rpc_timeout, read_frame(&mut stream)).await
+        {
+            let reply = match bincode::deserialize::<Packet>(&frame) {
+                Ok(Packet::Handshake(handshake)) => {
+                    self.accept_handshake(handshake, src).map(|reply| {
+                        bincode::serialize(&Packet::Handshake(reply))
+                            .expect("failed to serialize data")
+                    })
+                }
+                Ok(packet) => match self.open_packet(packet, src) {
+                    Some((sender, request @ RpcMessage::Request { .. }, encrypted)) => {
+                        let requester = Some(sender.id);
+                        let reply = match self.admit(src, &sender, &request, encrypted) {
+                            Ok(permit) => {
+                                let reply =
+                                    self.handle_request(tx.clone(), src, sender, request).await;
+                                drop(permit);
+                                reply
+                            }
+                            Err(refusal) => refusal,
+                        };
+                        reply.map(|reply| self.encode(Some(&src), requester, &reply))
+                    }
+                    _ => None,
+                },
+                Err(_) => {
+                    println!("Dropping malformed frame from {}", src);
+                    None
+                }
+            };
+            let Some(reply) = reply else {
+                break;
+            };
+            if write_frame(&mut stream, &reply).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    // whether contact is in our routing table
+    async fn knows(&self, contact: Contact) -> bool {
+        let Some(tx) = self.route_table_tx.get() else {
+            return false;
+        };
+        let (reply_tx, mut reply_rx) = mpsc::channel(1);
+        let _ = tx.send(RouteTableCMD::HasContact(contact, reply_tx)).await;
+        reply_rx.recv().await.unwrap_or(false)
+    }
 
-            match received_msg {
-                RpcMessage::Request {
+    // whether to PING an address a contact advertised, each one is checked once
+    // and an IP gets only a few such PINGs however many peers name it
+    fn should_ping_back(&self, contact: &Contact) -> bool {
+        let mut ping_backs = self.ping_backs.lock().unwrap();
+        if ping_backs.get(&contact.id) == Some(&contact.address) {
+            return false;
+        }
+        if !self
+            .ping_back_limiter
+            .lock()
+            .unwrap()
+            .allow(contact.address.ip(), Instant::now())
+        {
+            return false;
+        }
+        if ping_backs.len() >= MAX_PING_BACKS {
+            ping_backs.clear();
+        }
+        ping_backs.insert(contact.id, contact.address);
+        true
+    }
+
+    fn observe_address(&self, src: SocketAddr, observed: SocketAddr) {
+        let mut discovery = self.address_discovery.lock().unwrap();
+        let previous = discovery.advertised();
+        if let Some(external) = discovery.observe(src.ip(), observed) {
+            println!(
+                "Peers see us at {}, advertising it instead of {}",
+                external, previous
+            );
+        }
+    }
+
+    // requests are limited before they cause any routing table or disk work,
+    // a refusal carries the error to answer with, if any
+    fn admit(
+        &self,
+        src: SocketAddr,
+        sender: &RpcSender,
+        request: &RpcMessage,
+        encrypted: bool,
+    ) -> Result<OwnedSemaphorePermit, Option<RpcMessage>> {
+        let RpcMessage::Request { rpc_id, method, .. } = request else {
+            return Err(None);
+        };
+        let refuse = |message: &str| {
+            Err(Some(RpcMessage::Error {
+                rpc_id: *rpc_id,
+                message: message.to_string(),
+            }))
+        };
+
+        // anyone can send to a client, so it drops requests without a word
+        if self.client {
+            return Err(None);
+        }
+        if self.encrypt && !encrypted {
+            println!("Dropping unencrypted {:?} request from {}", method, src);
+            return Err(None);
+        }
+        let allowed = self
+            .rate_limiter
+            .lock()
+            .unwrap()
+            .allow(src.ip(), Instant::now());
+        if !allowed {
+            println!("Rate limiting {:?} from {}", method, src);
+            return refuse("rate limited");
+        }
+        // a replayed STORE or DELETE would change what we hold, those have
+        // to be addressed to us
+        if matches!(method, Command::STORE | Command::DELETE) && sender.recipient.is_none() {
+            return refuse("request is not addressed to this node");
+        }
+        let first_sighting = self.seen_requests.lock().unwrap().first_sighting(
+            sender.id,
+            *rpc_id,
+            sender.timestamp,
+            utils::unix_time(),
+        );
+        if !first_sighting {
+            println!("Dropping replayed {:?} request from {}", method, src);
+            return Err(None);
+        }
+        match Arc::clone(&self.handler_permits).try_acquire_owned() {
+            Ok(permit) => Ok(permit),
+            Err(_) => {
+                println!(
+                    "Too many requests in flight, rejecting {:?} from {}",
+                    method, src
+                );
+                refuse("too busy")
+            }
+        }
+    }
+
+    // hands a response, error or UseStream to the request it answers,
+    // false when it answers none
+    async fn handle_reply(&self, src: SocketAddr, sender: RpcSender, reply: RpcMessage) -> bool {
+        match reply {
+            RpcMessage::Response {
+                rpc_id,
+                result,
+                data,
+                contact,
+            } => {
+                // unsolicited, late or misdirected responses are not trusted at all
+                let Some(reply_tx) = self.take_pending(&rpc_id, src, Some(result)) else {
+                    println!(
+                        "Dropping {:?} response from {} that matches no request",
+                        result, src
+                    );
+                    return false;
+                };
+
+                // only the signer of a response is verified, contacts it lists
+                // stay in the lookup shortlist until they answer us themselves.
+                // the responder goes in before its caller is woken up
+                if let Some(tx) = self.route_table_tx.get().filter(|_| !sender.client) {
+                    let responder = Contact::with_nonce(sender.id, src, sender.nonce);
+                    let _ = tx.send(RouteTableCMD::AddContact(responder)).await;
+                }
+                let _ = reply_tx.send(RpcMessage::Response {
                     rpc_id,
-                    method,
-                    target_id,
-                    data,
-                    contact: cntact,
-                } => match method {
-                    Command::PING => {
+                    result,
+                    data: data.clone(),
+                    contact: contact.clone(),
+                });
+
+                match result {
+                    Command::PONG => {
                         println!(
-                            "Received {:?} Request from {} rpc id {}",
-                            method,
+                            "Received {:?} Response from {} rpc id {}",
+                            result,
                             src,
                             rpc_id.to_hex()
                         );
-                        let contact_vec = cntact.unwrap();
-
-                        let contact = &contact_vec[0];
-
-                        let _ = tx.send(RouteTableCMD::AddContact(contact.clone())).await;
-
-                        tokio::spawn(async move {
-                            Networking::send_rpc_response(
-                                rpc_id,
-                                &src.ip().to_string(),
-                                Command::PONG,
-                                None,
-                                None,
-                            )
-                            .await
-                            .expect("no response was sent");
-                        });
-
-                        println!("Sent PONG to {}", &src.ip().to_string());
                     }
                     Command::FINDNODE => {
                         println!(
-                            "Received {:?} Request from {} rpc id {}",
-                            method,
+                            "Received {:?} Response from {} rpc id {}",
+                            result,
                             src,
                             rpc_id.to_hex()
                         );
 
-                        if let Some(target_id) = target_id {
-                            let (reply_tx, mut reply_rx) = mpsc::channel::<Vec<Contact>>(1);
-
-                            let _ = tx
-                                .send(RouteTableCMD::GetClosestNodes(target_id, reply_tx))
-                                .await;
-
-                            if let Some(contacts) = reply_rx.recv().await {
-                                let src_ip = src.to_string();
-                                let own_id_copy = rpc_id.clone();
-                                tokio::spawn(async move {
-                                    Networking::send_rpc_response(
-                                        own_id_copy,
-                                        &src_ip,
-                                        Command::FINDNODE,
-                                        None,
-                                        Some(contacts),
-                                    )
-                                    .await
-                                    .expect("no response was sent");
-                                });
-                            } else {
-                                println!("no contacts from routing table");
-                            }
-                        } else {
-                            println!("{:?} request missing target_id", method);
+                        if contact.is_none() {
+                            println!("{:?} missing contacts", result);
                         }
                     }
                     Command::FINDVALUE => {
                         println!(
-                            "Received {:?} Request from {} rpc id {}",
-                            method,
+                            "Received {:?} Response from {} rpc id {}",
+                            result,
                             src,
                             rpc_id.to_hex()
                         );
 
-                        if let Some(target_id) = target_id {
-                            let dir = "data";
-                            let filename = format!("{}/{}.txt", dir, target_id.to_hex());
-
-                            if let Ok(data) = tokio::fs::read_to_string(&filename).await {
-                                let src_ip = src.to_string();
-                                let own_id_copy = rpc_id.clone();
-                                tokio::spawn(async move {
-                                    Networking::send_rpc_response(
-                                        own_id_copy,
-                                        &src_ip,
-                                        Command::FINDVALUE,
-                                        Some(data),
-                                        None,
-                                    )
-                                    .await
-                                    .expect("no response was sent");
-                                });
-                            } else {
-                                let (reply_tx, mut reply_rx) = mpsc::channel::<Vec<Contact>>(1);
-
-                                let _ = tx
-                                    .send(RouteTableCMD::GetClosestNodes(target_id, reply_tx))
-                                    .await;
-
-                                if let Some(contacts) = reply_rx.recv().await {
-                                    let contacts_cp = contacts.clone();
-                                    println!("contacts: {:?}", contacts_cp);
-                                    let src_ip = src.to_string();
-                                    let own_id_copy = rpc_id.clone();
-
-                                    tokio::spawn(async move {
-                                        Networking::send_rpc_response(
-                                            own_id_copy,
-                                            &src_ip,
-                                            Command::FINDVALUE,
-                                            None,
-                                            Some(contacts),
-                                        )
-                                        .await
-                                        .expect("no response was sent");
-                                    });
-                                } else {
-                                    println!("no contacts from routing table");
-                                }
-                            }
-                        } else {
-                            println!("{:?} request missing target_id", method);
+                        if data.is_none() && contact.is_none() {
+                            println!("{:?} response missing data and contacts", result);
                         }
                     }
                     Command::STORE => {
                         println!(
-                            "Received {:?} Request from {} rpc id {}",
-                            method,
+                            "Received {:?} Response from {} rpc id {}",
+                            result,
                             src,
                             rpc_id.to_hex()
                         );
-                        if let Some(data) = data {
-                            if let Some(target_id) = target_id {
-                                let dir = "data";
-                                let filename = format!("{}/{}.txt", dir, target_id.to_hex());
-
-                                match fs::create_dir_all(dir).await {
-                                    Ok(_) => {
-                                        eprintln!("Directory '{}' created or already exists", dir);
-                                    }
-                                    Err(e) => {
-                                        eprintln!("Failed to create directory '{}': {}", dir, e);
-                                    }
-                                }
-
-                                match fs::write(&filename, data).await {
-                                    Ok(_) => {
-                                        eprintln!("Data successfully stored in file: {}", filename);
-                                    }
-                                    Err(e) => {
-                                        eprintln!("Failed to store data in '{}': {}", filename, e);
-                                    }
-                                }
-
-                                let src_ip = src.ip().to_string();
-                                let own_id_copy = rpc_id.clone();
-                                tokio::spawn(async move {
-                                    Networking::send_rpc_response(
-                                        own_id_copy,
-                                        &src_ip,
-                                        Command::STORE,
-                                        None,
-                                        None,
-                                    )
-                                    .await
-                                    .expect("Failed to send STORE response");
-                                });
-                            } else {
-                                println!("STORE request missing target_id");
-                            }
-                        } else {
-                            println!("STORE request missing data");
-                        }
                     }
-
                     _ => {
-                        println!("Received unexpected command from {}", src);
+                        println!(
+                            "Received Response with ID {} and result: {:?}",
+                            rpc_id.to_hex(),
+                            result
+                        );
                     }
-                },
-                RpcMessage::Response {
-                    rpc_id,
-                    result,
-                    data,
-                    contact,
-                } => {
-                    let sender_opt = {
-                        let mut map = self.response_map.lock().await;
-                        map.remove(&rpc_id)
-                    };
-
-                    if let Some(sender) = sender_opt {
-                        let response_message = RpcMessage::Response {
-                            rpc_id: rpc_id.clone(),
-                            result,
-                            data: data.clone(),
-                            contact: contact.clone(),
-                        };
+                }
+                true
+            }
+            RpcMessage::Error { rpc_id, message } => {
+                println!("Received Error with ID {}: {}", rpc_id.to_hex(), message);
 
-                        let _ = sender.send(response_message);
-                    } else {
-                        // println!("No waiting sender for rpc_id {}", rpc_id.to_hex());
+                match self.take_pending(&rpc_id, src, None) {
+                    Some(reply_tx) => {
+                        let _ = reply_tx.send(RpcMessage::Error { rpc_id, message });
+                        true
                     }
+                    None => {
+                        println!("Dropping error from {} that matches no request", src);
+                        false
+                    }
+                }
+            }
+            RpcMessage::UseStream { rpc_id } => match self.take_pending(&rpc_id, src, None) {
+                Some(reply_tx) => {
+                    let _ = reply_tx.send(RpcMessage::UseStream { rpc_id });
+                    true
+                }
+                None => false,
+            },
+            RpcMessage::Request { .. } => false,
+        }
+    }
 
-                    match result {
-                        Command::PONG => {
-                            println!(
-                                "Received {:?} Response from {} rpc id {}",
-                                result,
-                                src,
-                                rpc_id.to_hex()
-                            );
-                            let src_ip = src.ip().to_string();
-                            let contact = Contact::new(rpc_id, src_ip.clone());
-                            let _ = tx.send(RouteTableCMD::AddContact(contact)).await;
-                        }
-                        Command::FINDNODE => {
-                            println!(
-                                "Received {:?} Response from {} rpc id {}",
-                                result,
-                                src,
-                                rpc_id.to_hex()
-                            );
-
-                            if let Some(contacts) = contact {
-                                for contact in &contacts {
-                                    let _ =
-                                        tx.send(RouteTableCMD::AddContact(contact.clone())).await;
-                                }
-                            } else {
-                                println!("{:?} missing contacts", result);
-                            }
-                        }
-                        Command::FINDVALUE => {
-                            println!(
-                                "Received {:?} Response from {} rpc id {}",
-                                result,
-                                src,
-                                rpc_id.to_hex()
-                            );
-
-                            if let Some(_) = data {
-                                // continue
-                            } else if let Some(contacts) = contact {
-                                println!("contacts: {:?}", contacts);
-
-                                for contact in &contacts {
-                                    let _ =
-                                        tx.send(RouteTableCMD::AddContact(contact.clone())).await;
-                                }
-                            } else {
-                                println!("{:?} response missing data and contacts", result);
-                            }
-                        }
-                        Command::STORE => {
-                            println!(
-                                "Received {:?} Response from {} rpc id {}",
-                                result,
-                                src,
-                                rpc_id.to_hex()
-                            );
-                        }
-                        _ => {
-                            println!(
-                                "Received Response with ID {} and result: {:?}",
-                                rpc_id.to_hex(),
-                                result
-                            );
-                        }
+    // the response or error to answer the request with, None when it gets none
+    async fn handle_request(
+        &self,
+        tx: mpsc::Sender<RouteTableCMD>,
+        src: SocketAddr,
+        sender: RpcSender,
+        request: RpcMessage,
+    ) -> Option<RpcMessage> {
+        let RpcMessage::Request {
+            rpc_id,
+            method,
+            target_id,
+            data,
+            contact: cntact,
+        } = request
+        else {
+            return None;
+        };
+        let RpcSender {
+            id: sender_id,
+            nonce: sender_nonce,
+            client: sender_client,
+            ..
+        } = sender;
+        println!(
+            "Received {:?} Request from {} rpc id {}",
+            method,
+            src,
+            rpc_id.to_hex()
+        );
+        let respond = |result, data, contact| {
+            Some(RpcMessage::Response {
+                rpc_id,
+                result,
+                data,
+                contact,
+            })
+        };
+        let refuse = |message| Some(RpcMessage::Error { rpc_id, message });
+
+        match method {
+            Command::PING => {
+                // the advertised contact has to belong to whoever signed the PING
+                match cntact.as_ref().and_then(|c| c.first()) {
+                    // clients want answers, not a place in our buckets
+                    _ if sender_client => println!("Not adding client {}", src),
+                    Some(contact) if contact.id == sender_id && contact.address == src => {
+                        let contact = Contact::with_nonce(sender_id, contact.address, sender_nonce);
+                        let _ = tx.send(RouteTableCMD::AddContact(contact)).await;
                     }
+                    // an address we did not hear from may be behind a NAT, it only
+                    // goes in once it answers a PING of ours
+                    Some(contact) if contact.id == sender_id && !self.should_ping_back(contact) => {
+                        println!("Not checking {} again for {}", contact.address, src);
+                    }
+                    Some(contact) if contact.id == sender_id => {
+                        let networking = self.clone();
+                        let contact = contact.clone();
+                        tokio::spawn(async move {
+                            let _ = networking
+                                .send_rpc_request_to_contact(
+                                    KademliaID::new(),
+                                    &contact,
+                                    Command::PING,
+                                    None,
+                                    None,
+                                    None,
+                                )
+                                .await;
+                        });
+                    }
+                    Some(_) => println!("PING from {} with a contact it does not own", src),
+                    None => {}
                 }
-                RpcMessage::Error { rpc_id, message } => {
-                    println!("Received Error with ID {}: {}", rpc_id.to_hex(), message);
+
+                // the address we saw tells the peer how it is reached from outside
+                println!("Sending PONG to {}", src);
+                respond(Command::PONG, Some(src.to_string()), None)
+            }
+            Command::FINDNODE => {
+                let Some(target_id) = target_id else {
+                    println!("{:?} request missing target_id", method);
+                    return None;
+                };
+                let (reply_tx, mut reply_rx) = mpsc::channel::<Vec<Contact>>(1);
+                let _ = tx
+                    .send(RouteTableCMD::GetClosestNodes(target_id, reply_tx))
+                    .await;
+
+                match reply_rx.recv().await {
+                    Some(contacts) => respond(Command::FINDNODE, None, Some(contacts)),
+                    None => {
+                        println!("no contacts from routing table");
+                        None
+                    }
                 }
             }
+            Command::FINDVALUE => {
+                let Some(target_id) = target_id else {
+                    println!("{:?} request missing target_id", method);
+                    return None;
+                };
+                let filename = storage::value_path(&self.storage_dir, &target_id);
+
+                if let Ok(data) = tokio::fs::read_to_string(&filename).await {
+                    return respond(Command::FINDVALUE, Some(data), None);
+                }
+
+                let (reply_tx, mut reply_rx) = mpsc::channel::<Vec<Contact>>(1);
+                let _ = tx
+                    .send(RouteTableCMD::GetClosestNodes(target_id, reply_tx))
+                    .await;
+
+                match reply_rx.recv().await {
+                    Some(contacts) => {
+                        println!("contacts: {:?}", contacts);
+                        respond(Command::FINDVALUE, None, Some(contacts))
+                    }
+                    None => {
+                        println!("no contacts from routing table");
+                        None
+                    }
+                }
+            }
+            Command::STORE => {
+                let (Some(target_id), Some(data)) = (target_id, data) else {
+                    println!("STORE request missing target_id or data");
+                    return None;
+                };
+
+                let refusal = if data.len() > self.max_store_size {
+                    Some(format!(
+                        "value of {} bytes is larger than {}",
+                        data.len(),
+                        self.max_store_size
+                    ))
+                } else if !self
+                    .store_quota
+                    .lock()
+                    .unwrap()
+                    .allows(&sender_id, src.ip(), &target_id)
+                {
+                    Some("too many values stored by this peer or its network".to_string())
+                } else {
+                    None
+                };
+                let result = match refusal {
+                    Some(message) => Err(message),
+                    None => {
+                        storage::store_value(&self.storage_dir, &target_id, &data, Some(sender_id))
+                            .await
+                            .map_err(|e| e.to_string())
+                    }
+                };
+
+                match result {
+                    Ok(_) => {
+                        self.store_quota
+                            .lock()
+                            .unwrap()
+                            .add(sender_id, src.ip(), target_id);
+                        eprintln!(
+                            "Data successfully stored in file: {}",
+                            storage::value_path(&self.storage_dir, &target_id).display()
+                        );
+                        // only acknowledge what we actually stored
+                        respond(Command::STORE, None, None)
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to store {}: {}", target_id.to_hex(), e);
+                        refuse(e)
+                    }
+                }
+            }
+            Command::DELETE => {
+                let Some(target_id) = target_id else {
+                    println!("DELETE request missing target_id");
+                    return None;
+                };
+
+                match storage::delete_value(&self.storage_dir, &target_id, &sender_id).await {
+                    Ok(_) => {
+                        self.store_quota
+                            .lock()
+                            .unwrap()
+                            .remove(&sender_id, src.ip(), &target_id);
+                        respond(Command::DELETE, None, None)
+                    }
+                    Err(e) => {
+                        println!("Refused to delete {}: {}", target_id.to_hex(), e);
+                        refuse(e.to_string())
+                    }
+                }
+            }
+
+            _ => {
+                println!("Received unexpected command from {}", src);
+                None
+            }
         }
     }
 }
+
+fn expected_result(command: Command) -> Command {
+    match command {
+        Command::PING => Command::PONG,
+        command => command,
+    }
+}
+
+fn no_session(addr: SocketAddr) -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::TimedOut,
+        format!("no encrypted session with {}", addr),
+    )
+}
+
+async fn resolve(target_addr: impl ToSocketAddrs) -> std::io::Result<Option<SocketAddr>> {
+    Ok(lookup_host(target_addr).await?.next().map(canonical))
+}
+
+// v4-mapped addresses of ipv4 peers on a dual-stack socket become plain
+// ipv4 ones, so a peer has the same address whichever socket it talks to
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+// sockets bound to an ipv6 address take ipv4 peers as well
+fn bind_socket(addr: SocketAddr, ty: Type) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    if ty == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+// frames on a stream are a u32 big endian length and that many bytes
+async fn read_frame(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let len = stream.read_u32().await? as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("frame of {} bytes is too large", len),
+        ));
+    }
+    let mut frame = vec![0u8; len];
+    stream.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+async fn write_frame(stream: &mut TcpStream, frame: &[u8]) -> std::io::Result<()> {
+    if frame.len() > MAX_FRAME_SIZE {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("frame of {} bytes is too large", frame.len()),
+        ));
+    }
+    stream.write_u32(frame.len() as u32).await?;
+    stream.write_all(frame).await
+}
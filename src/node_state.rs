@@ -0,0 +1,86 @@
+use {
+    crate::{
+        constants::{LEGACY_NODE_ID_FILE, NODE_KEY_FILE, ROUTING_TABLE_FILE},
+        contact::Contact,
+        kademlia_id::KademliaID,
+        puzzle, utils,
+    },
+    ed25519_dalek::SigningKey,
+    std::{
+        io::{Error, ErrorKind},
+        path::Path,
+    },
+    tokio::fs,
+};
+
+// the node id is derived from this key, so keeping the key keeps the id
+pub async fn load_or_create_node_key(
+    state_dir: &Path,
+    regenerate: bool,
+    static_puzzle_difficulty: u32,
+) -> std::io::Result<SigningKey> {
+    let path = state_dir.join(NODE_KEY_FILE);
+
+    if !regenerate {
+        match fs::read_to_string(&path).await {
+            Ok(hex) => {
+                return utils::signing_key_from_hex(hex.trim()).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid node key in '{}'", path.display()),
+                    )
+                });
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let signing_key = puzzle::generate_key(static_puzzle_difficulty);
+    fs::create_dir_all(state_dir).await?;
+
+    // write to a temporary file first so a crash never leaves a truncated key behind
+    let tmp_path = state_dir.join(format!("{}.tmp", NODE_KEY_FILE));
+    fs::write(&tmp_path, utils::to_hex(signing_key.as_bytes())).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(0o600)).await?;
+    }
+    fs::rename(&tmp_path, &path).await?;
+
+    let kad_id = KademliaID::from_public_key(&signing_key.verifying_key());
+    println!("Generated new node key, node id {}", kad_id.to_hex());
+
+    let legacy_path = state_dir.join(LEGACY_NODE_ID_FILE);
+    if let Ok(legacy_id) = fs::read_to_string(&legacy_path).await {
+        eprintln!(
+            "'{}' is obsolete, ids are derived from the node key now: this node changes its id from {} to {}",
+            legacy_path.display(),
+            legacy_id.trim(),
+            kad_id.to_hex()
+        );
+    }
+    Ok(signing_key)
+}
+
+pub async fn save_routing_table(state_dir: &Path, contacts: &[Contact]) -> std::io::Result<()> {
+    let bin_data = bincode::serialize(contacts)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    fs::create_dir_all(state_dir).await?;
+
+    let path = state_dir.join(ROUTING_TABLE_FILE);
+    let tmp_path = state_dir.join(format!("{}.tmp", ROUTING_TABLE_FILE));
+    fs::write(&tmp_path, bin_data).await?;
+    fs::rename(&tmp_path, &path).await
+}
+
+pub async fn load_routing_table(state_dir: &Path) -> std::io::Result<Vec<Contact>> {
+    let path = state_dir.join(ROUTING_TABLE_FILE);
+    match fs::read(&path).await {
+        Ok(bin_data) => bincode::deserialize(&bin_data)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
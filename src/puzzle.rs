@@ -0,0 +1,61 @@
+use {
+    crate::{contact::Contact, kademlia_id::KademliaID},
+    ed25519_dalek::SigningKey,
+    sha2::{Digest, Sha256},
+};
+
+// S/Kademlia style puzzles, both are off with a difficulty of 0
+//
+// static:  H(node id) starts with static_difficulty zero bits, since the id
+//          is H(public key) this makes every new identity expensive
+// dynamic: H(node id ^ nonce) starts with dynamic_difficulty zero bits, the
+//          nonce travels with the contact
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in bytes {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+pub fn solves_static(kad_id: &KademliaID, difficulty: u32) -> bool {
+    difficulty == 0 || leading_zero_bits(&Sha256::digest(kad_id.id)) >= difficulty
+}
+
+pub fn solves_dynamic(kad_id: &KademliaID, nonce: u64, difficulty: u32) -> bool {
+    if difficulty == 0 {
+        return true;
+    }
+    let mut x = kad_id.id;
+    let nonce_bytes = nonce.to_be_bytes();
+    let offset = x.len() - nonce_bytes.len();
+    for (byte, nonce_byte) in x[offset..].iter_mut().zip(nonce_bytes) {
+        *byte ^= nonce_byte;
+    }
+    leading_zero_bits(&Sha256::digest(x)) >= difficulty
+}
+
+pub fn verify_contact(contact: &Contact, static_difficulty: u32, dynamic_difficulty: u32) -> bool {
+    solves_static(&contact.id, static_difficulty)
+        && solves_dynamic(&contact.id, contact.nonce, dynamic_difficulty)
+}
+
+pub fn generate_key(static_difficulty: u32) -> SigningKey {
+    loop {
+        let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let kad_id = KademliaID::from_public_key(&signing_key.verifying_key());
+        if solves_static(&kad_id, static_difficulty) {
+            return signing_key;
+        }
+    }
+}
+
+pub fn solve_dynamic(kad_id: &KademliaID, difficulty: u32) -> u64 {
+    (0..)
+        .find(|nonce| solves_dynamic(kad_id, *nonce, difficulty))
+        .expect("no nonce solves the dynamic puzzle")
+}
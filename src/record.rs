@@ -0,0 +1,81 @@
+use {
+    crate::{kademlia_id::KademliaID, utils},
+    ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey},
+    serde::{Deserialize, Serialize},
+};
+
+// records travel and are stored in the same string field as plain values,
+// so a plain value starting with the prefix is taken for a record and refused
+// unless it is a valid one
+const RECORD_PREFIX: &str = "record:";
+
+// a value stored under the hash of its publisher's public key, signed by that
+// key, newer sequence numbers replace older ones
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MutableRecord {
+    pub public_key: [u8; 32],
+    pub sequence: u64,
+    pub value: String,
+    signature: Vec<u8>,
+}
+
+impl MutableRecord {
+    pub fn new(signing_key: &SigningKey, sequence: u64, value: String) -> Self {
+        let public_key = signing_key.verifying_key().to_bytes();
+        let signature = signing_key
+            .sign(&signed_bytes(&public_key, sequence, &value))
+            .to_bytes()
+            .to_vec();
+        Self {
+            public_key,
+            sequence,
+            value,
+            signature,
+        }
+    }
+
+    pub fn key_for(public_key: &VerifyingKey) -> KademliaID {
+        KademliaID::from_public_key(public_key)
+    }
+
+    pub fn key(&self) -> KademliaID {
+        KademliaID::from_bytes(&self.public_key)
+    }
+
+    pub fn verify(&self) -> bool {
+        let Ok(public_key) = VerifyingKey::from_bytes(&self.public_key) else {
+            return false;
+        };
+        let Ok(signature) = Signature::from_slice(&self.signature) else {
+            return false;
+        };
+        public_key
+            .verify(
+                &signed_bytes(&self.public_key, self.sequence, &self.value),
+                &signature,
+            )
+            .is_ok()
+    }
+
+    pub fn encode(&self) -> String {
+        let bytes = bincode::serialize(self).expect("failed to serialize record");
+        format!("{}{}", RECORD_PREFIX, utils::to_hex(&bytes))
+    }
+
+    // None for plain values and for anything that does not parse as a record
+    pub fn decode(data: &str) -> Option<Self> {
+        let bytes = utils::from_hex(data.strip_prefix(RECORD_PREFIX)?)?;
+        bincode::deserialize(&bytes).ok()
+    }
+
+    pub fn is_record(data: &str) -> bool {
+        data.starts_with(RECORD_PREFIX)
+    }
+}
+
+fn signed_bytes(public_key: &[u8; 32], sequence: u64, value: &str) -> Vec<u8> {
+    let mut bytes = public_key.to_vec();
+    bytes.extend_from_slice(&sequence.to_be_bytes());
+    bytes.extend_from_slice(value.as_bytes());
+    bytes
+}
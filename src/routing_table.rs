@@ -1,25 +1,62 @@
 use crate::{
     bucket::Bucket,
-    constants::{ID_LENGTH, RT_BCKT_SIZE},
+    config::KademliaConfig,
+    constants::{BUCKET_SIZE, ID_LENGTH, RT_BCKT_SIZE},
     contact::Contact,
     contact::ContactCandidates,
     kademlia_id::KademliaID,
+    limits::DiversityLimits,
+    puzzle,
 };
 
 #[derive(Clone)]
 pub struct RoutingTable {
     me: Contact,
     buckets: [Option<Bucket>; RT_BCKT_SIZE],
+    bucket_size: usize,
+    static_puzzle_difficulty: u32,
+    dynamic_puzzle_difficulty: u32,
+    bucket_limits: DiversityLimits,
+    table_limits: DiversityLimits,
 }
 
 impl RoutingTable {
     pub fn new(me: Contact) -> Self {
+        Self::with_bucket_size(me, BUCKET_SIZE)
+    }
+
+    pub fn with_config(me: Contact, config: &KademliaConfig) -> Self {
+        Self {
+            static_puzzle_difficulty: config.static_puzzle_difficulty,
+            dynamic_puzzle_difficulty: config.dynamic_puzzle_difficulty,
+            bucket_limits: DiversityLimits {
+                per_ip: config.max_bucket_contacts_per_ip,
+                per_subnet: config.max_bucket_contacts_per_subnet,
+            },
+            table_limits: DiversityLimits {
+                per_ip: config.max_contacts_per_ip,
+                per_subnet: config.max_contacts_per_subnet,
+            },
+            ..Self::with_bucket_size(me, config.k)
+        }
+    }
+
+    pub fn with_bucket_size(me: Contact, bucket_size: usize) -> Self {
         Self {
             me,
             buckets: std::array::from_fn(|_| None),
+            bucket_size,
+            static_puzzle_difficulty: 0,
+            dynamic_puzzle_difficulty: 0,
+            bucket_limits: DiversityLimits::default(),
+            table_limits: DiversityLimits::default(),
         }
     }
 
+    pub fn bucket_size(&self) -> usize {
+        self.bucket_size
+    }
+
     pub fn get_bucket_index(&self, id: KademliaID) -> usize {
         let distance = self.me.id.distance(&id);
 
@@ -29,28 +66,73 @@ impl RoutingTable {
             .flat_map(|&byte| (0..8).rev().map(move |i| (byte >> i) & 1))
             .position(|bit| bit != 0)
         {
-            let bucket_index = (ID_LENGTH * 8 - 1) - position;
-            bucket_index
+            (ID_LENGTH * 8 - 1) - position
         } else {
             0
         }
     }
 
-
     pub fn add_contact(&mut self, contact: Contact) {
-        let index: usize = self.get_bucket_index(contact.id.clone());
+        if !puzzle::verify_contact(
+            &contact,
+            self.static_puzzle_difficulty,
+            self.dynamic_puzzle_difficulty,
+        ) {
+            println!(
+                "Contact {} does not solve the id puzzle, not adding it",
+                contact.id.to_hex()
+            );
+            return;
+        }
+        // keep a single host or subnet from filling the table with made up ids
+        if !self.table_limits.admits(self.contacts().iter(), &contact) {
+            println!(
+                "Routing table already has enough contacts from {}, not adding {}",
+                contact.address,
+                contact.id.to_hex()
+            );
+            return;
+        }
+        let index: usize = self.get_bucket_index(contact.id);
         match &mut self.buckets[index] {
             Some(bucket) => {
                 bucket.add_contact(&contact, contact.id);
             }
             None => {
-                let mut bucket = Bucket::new();
+                let mut bucket = Bucket::with_limits(self.bucket_size, self.bucket_limits);
                 bucket.add_contact(&contact, contact.id);
                 self.buckets[index] = Some(bucket);
             }
         }
     }
 
+    pub fn remove_contact(&mut self, id: KademliaID) {
+        let index = self.get_bucket_index(id);
+        if let Some(bucket) = &mut self.buckets[index] {
+            bucket.remove_contact(id);
+        }
+    }
+
+    // whether the table has this id at this address
+    pub fn has_contact(&self, contact: &Contact) -> bool {
+        self.buckets[self.get_bucket_index(contact.id)]
+            .as_ref()
+            .is_some_and(|bucket| {
+                bucket
+                    .contacts()
+                    .iter()
+                    .any(|c| c.id == contact.id && c.address == contact.address)
+            })
+    }
+
+    pub fn contacts(&self) -> Vec<Contact> {
+        self.buckets
+            .iter()
+            .flatten()
+            .flat_map(|bucket| bucket.contacts())
+            .collect()
+    }
+
     pub fn find_closest_contacts(&mut self, target: KademliaID, count: usize) -> Vec<Contact> {
         let mut candidates = ContactCandidates::new();
         let bucket_index = self.get_bucket_index(target);
@@ -1,14 +1,14 @@
 use tokio::sync::mpsc;
 
-use crate::{
-    constants::BUCKET_SIZE, contact::Contact, kademlia_id::KademliaID, routing_table::RoutingTable,
-};
+use crate::{contact::Contact, kademlia_id::KademliaID, routing_table::RoutingTable};
 
 pub enum RouteTableCMD {
     AddContact(Contact),
     RemoveContact(KademliaID),
     GetClosestNodes(KademliaID, mpsc::Sender<Vec<Contact>>),
     GetBucketIndex(KademliaID, mpsc::Sender<usize>),
+    GetAllContacts(mpsc::Sender<Vec<Contact>>),
+    HasContact(Contact, mpsc::Sender<bool>),
 }
 
 pub async fn routing_table_handler(
@@ -20,17 +20,24 @@ pub async fn routing_table_handler(
             RouteTableCMD::AddContact(contact) => {
                 routing_table.add_contact(contact);
             }
-            RouteTableCMD::RemoveContact(_kad_id) => {
-                //                println!("Remove  contact");
+            RouteTableCMD::RemoveContact(kad_id) => {
+                routing_table.remove_contact(kad_id);
             }
             RouteTableCMD::GetClosestNodes(target_id, reply) => {
-                let contacts = routing_table.find_closest_contacts(target_id, BUCKET_SIZE);
+                let bucket_size = routing_table.bucket_size();
+                let contacts = routing_table.find_closest_contacts(target_id, bucket_size);
                 let _ = reply.send(contacts).await;
             }
             RouteTableCMD::GetBucketIndex(kad_id, reply) => {
                 let index = routing_table.get_bucket_index(kad_id);
                 let _ = reply.send(index).await;
             }
+            RouteTableCMD::GetAllContacts(reply) => {
+                let _ = reply.send(routing_table.contacts()).await;
+            }
+            RouteTableCMD::HasContact(contact, reply) => {
+                let _ = reply.send(routing_table.has_contact(&contact)).await;
+            }
         }
     }
 }
@@ -1,7 +1,9 @@
 use crate::constants::rpc::Command;
 use crate::contact::Contact;
 use crate::kademlia_id::KademliaID;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
 #[derive(Serialize, Deserialize, Debug)]
 pub enum RpcMessage {
     Request {
@@ -21,4 +23,169 @@ pub enum RpcMessage {
         rpc_id: KademliaID,
         message: String,
     },
+    // the response does not fit in a datagram, ask again over a stream
+    UseStream {
+        rpc_id: KademliaID,
+    },
+}
+
+pub struct RpcSender {
+    pub id: KademliaID,
+    pub nonce: u64,
+    pub tcp: bool,
+    pub client: bool,
+    pub recipient: Option<KademliaID>,
+    pub timestamp: u64,
+}
+
+// what actually goes over the wire, the sender id is the hash of public_key,
+// nonce is its solution to the dynamic id puzzle, tcp tells whether it also
+// accepts streams on the same port and client that it serves no requests
+// and must not be added to routing tables. recipient, when the sender knows
+// it, and the unix time it was sent keep a captured message from being
+// replayed to another node or much later
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SignedRpc {
+    public_key: [u8; 32],
+    nonce: u64,
+    tcp: bool,
+    client: bool,
+    recipient: Option<KademliaID>,
+    timestamp: u64,
+    payload: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+// the fields of a SignedRpc besides its message
+pub struct Envelope {
+    pub nonce: u64,
+    pub tcp: bool,
+    pub client: bool,
+    pub recipient: Option<KademliaID>,
+    pub timestamp: u64,
+}
+
+impl SignedRpc {
+    pub fn seal(signing_key: &SigningKey, envelope: Envelope, message: &RpcMessage) -> Self {
+        let payload = bincode::serialize(message).expect("failed to serialize rpc message");
+        let Envelope {
+            nonce,
+            tcp,
+            client,
+            recipient,
+            timestamp,
+        } = envelope;
+        Self {
+            public_key: signing_key.verifying_key().to_bytes(),
+            nonce,
+            tcp,
+            client,
+            recipient,
+            timestamp,
+            signature: signing_key
+                .sign(&signed_bytes(&envelope, &payload))
+                .to_bytes()
+                .to_vec(),
+            payload,
+        }
+    }
+
+    // the verified sender and message, None when the signature does not check out
+    pub fn open(&self) -> Option<(RpcSender, RpcMessage)> {
+        let public_key = VerifyingKey::from_bytes(&self.public_key).ok()?;
+        let signature = Signature::from_slice(&self.signature).ok()?;
+        let envelope = Envelope {
+            nonce: self.nonce,
+            tcp: self.tcp,
+            client: self.client,
+            recipient: self.recipient,
+            timestamp: self.timestamp,
+        };
+        public_key
+            .verify(&signed_bytes(&envelope, &self.payload), &signature)
+            .ok()?;
+        let message = bincode::deserialize(&self.payload).ok()?;
+        let sender = RpcSender {
+            id: KademliaID::from_public_key(&public_key),
+            nonce: self.nonce,
+            tcp: self.tcp,
+            client: self.client,
+            recipient: self.recipient,
+            timestamp: self.timestamp,
+        };
+        Some((sender, message))
+    }
+}
+
+fn signed_bytes(envelope: &Envelope, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = envelope.nonce.to_be_bytes().to_vec();
+    bytes.push(envelope.tcp as u8);
+    bytes.push(envelope.client as u8);
+    match envelope.recipient {
+        Some(recipient) => {
+            bytes.push(1);
+            bytes.extend_from_slice(&recipient.id);
+        }
+        None => bytes.push(0),
+    }
+    bytes.extend_from_slice(&envelope.timestamp.to_be_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+// requests seen within the replay window, a request signed by the same
+// sender with the same rpc id is only handled once
+pub struct SeenRequests {
+    window: u64,
+    capacity: usize,
+    seen: HashSet<(KademliaID, KademliaID)>,
+    // arrival time of every entry, oldest first
+    arrivals: VecDeque<(u64, (KademliaID, KademliaID))>,
+}
+
+impl SeenRequests {
+    pub fn new(window: u64, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity,
+            seen: HashSet::new(),
+            arrivals: VecDeque::new(),
+        }
+    }
+
+    // whether a message sent at timestamp is recent enough to be handled at now
+    pub fn is_fresh(&self, timestamp: u64, now: u64) -> bool {
+        timestamp.abs_diff(now) <= self.window
+    }
+
+    // false when the request is stale or was already seen
+    pub fn first_sighting(
+        &mut self,
+        sender: KademliaID,
+        rpc_id: KademliaID,
+        timestamp: u64,
+        now: u64,
+    ) -> bool {
+        if !self.is_fresh(timestamp, now) {
+            return false;
+        }
+        // a request accepted now stays fresh for up to two windows
+        while let Some(&(arrival, key)) = self.arrivals.front() {
+            if arrival + 2 * self.window >= now && self.arrivals.len() < self.capacity {
+                break;
+            }
+            self.arrivals.pop_front();
+            self.seen.remove(&key);
+        }
+        if !self.seen.insert((sender, rpc_id)) {
+            return false;
+        }
+        self.arrivals.push_back((now, (sender, rpc_id)));
+        true
+    }
+
+    // the request may come again, we asked for it over a stream
+    pub fn forget(&mut self, sender: KademliaID, rpc_id: KademliaID) {
+        self.seen.remove(&(sender, rpc_id));
+    }
 }
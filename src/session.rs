@@ -0,0 +1,433 @@
+use {
+    crate::{kademlia_id::KademliaID, rpc::SignedRpc},
+    chacha20poly1305::{
+        aead::{Aead, KeyInit},
+        ChaCha20Poly1305, Key, Nonce,
+    },
+    ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey},
+    hkdf::Hkdf,
+    serde::{Deserialize, Serialize},
+    sha2::Sha256,
+    std::{
+        collections::{HashMap, VecDeque},
+        net::SocketAddr,
+        time::{Duration, Instant},
+    },
+    tokio::sync::oneshot,
+    x25519_dalek::{PublicKey, StaticSecret},
+};
+
+const SESSION_LIFETIME: Duration = Duration::from_secs(3600);
+const MAX_SESSIONS: usize = 10_000;
+// how far behind the newest message one may arrive and still be let in
+const REPLAY_WINDOW: u64 = 64;
+
+// everything that goes over the wire
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Packet {
+    Plain(SignedRpc),
+    Handshake(Handshake),
+    Sealed {
+        session_id: KademliaID,
+        nonce: [u8; 12],
+        ciphertext: Vec<u8>,
+    },
+}
+
+// both sides send a fresh x25519 key signed with their node key and the
+// session key is derived from the two, so recorded traffic stays private
+// even if a node key leaks later. the reply also signs the key it answers,
+// so it cannot be replayed into another handshake
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Handshake {
+    pub session_id: KademliaID,
+    pub reply: bool,
+    public_key: [u8; 32],
+    ephemeral: [u8; 32],
+    signature: Vec<u8>,
+}
+
+impl Handshake {
+    fn new(
+        signing_key: &SigningKey,
+        session_id: KademliaID,
+        reply: bool,
+        secret: &StaticSecret,
+        peer_ephemeral: Option<&[u8; 32]>,
+    ) -> Self {
+        let ephemeral = PublicKey::from(secret).to_bytes();
+        Self {
+            session_id,
+            reply,
+            public_key: signing_key.verifying_key().to_bytes(),
+            ephemeral,
+            signature: signing_key
+                .sign(&signed_bytes(
+                    &session_id,
+                    reply,
+                    &ephemeral,
+                    peer_ephemeral,
+                ))
+                .to_bytes()
+                .to_vec(),
+        }
+    }
+
+    // the node id of the signer, None when the signature does not check out,
+    // a reply must have signed the key we sent
+    fn verify(&self, peer_ephemeral: Option<&[u8; 32]>) -> Option<KademliaID> {
+        let public_key = VerifyingKey::from_bytes(&self.public_key).ok()?;
+        let signature = Signature::from_slice(&self.signature).ok()?;
+        public_key
+            .verify(
+                &signed_bytes(
+                    &self.session_id,
+                    self.reply,
+                    &self.ephemeral,
+                    peer_ephemeral,
+                ),
+                &signature,
+            )
+            .ok()?;
+        Some(KademliaID::from_public_key(&public_key))
+    }
+}
+
+fn signed_bytes(
+    session_id: &KademliaID,
+    reply: bool,
+    ephemeral: &[u8; 32],
+    peer_ephemeral: Option<&[u8; 32]>,
+) -> Vec<u8> {
+    let mut bytes = session_id.id.to_vec();
+    bytes.push(reply as u8);
+    bytes.extend_from_slice(ephemeral);
+    if let Some(peer_ephemeral) = peer_ephemeral {
+        bytes.extend_from_slice(peer_ephemeral);
+    }
+    bytes
+}
+
+fn derive_key(secret: &StaticSecret, peer_ephemeral: &[u8; 32], session_id: &KademliaID) -> Key {
+    let shared = secret.diffie_hellman(&PublicKey::from(*peer_ephemeral));
+    let mut key = Key::default();
+    Hkdf::<Sha256>::new(Some(&session_id.id), shared.as_bytes())
+        .expand(b"kadrustlia session", &mut key)
+        .expect("32 bytes is a valid hkdf output length");
+    key
+}
+
+// both sides share the key, so the first nonce byte tells whose counter it
+// is and the two never use the same nonce
+fn nonce(initiator: bool, counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[0] = initiator as u8;
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+// the counters seen among the last REPLAY_WINDOW, bit i stands for next - 1 - i
+#[derive(Default)]
+struct ReplayWindow {
+    next: u64,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_new(&self, counter: u64) -> bool {
+        if counter >= self.next {
+            return true;
+        }
+        let age = self.next - 1 - counter;
+        age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+    }
+
+    fn mark(&mut self, counter: u64) {
+        if counter >= self.next {
+            let shift = counter - self.next + 1;
+            self.seen = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.next = counter + 1;
+        } else {
+            self.seen |= 1 << (self.next - 1 - counter);
+        }
+    }
+}
+
+struct Session {
+    cipher: ChaCha20Poly1305,
+    peer_id: KademliaID,
+    created: Instant,
+    initiator: bool,
+    sent: u64,
+    received: ReplayWindow,
+    addrs: Vec<SocketAddr>,
+}
+
+struct PendingHandshake {
+    secret: StaticSecret,
+    addr: SocketAddr,
+    // the node we meant to reach, None when we only know its address
+    expected: Option<KademliaID>,
+    done: oneshot::Sender<()>,
+}
+
+// the sessions to encrypt for an address with, one per node id verified
+// there and the one we set up last for when we do not know who to expect
+#[derive(Default)]
+struct Routes {
+    by_id: HashMap<KademliaID, KademliaID>,
+    ours: Option<KademliaID>,
+}
+
+// sessions are looked up by id to decrypt and by peer address and node id to
+// encrypt, a message only goes to the node it is meant for. they expire in the
+// order they were set up
+#[derive(Default)]
+pub struct Sessions {
+    sessions: HashMap<KademliaID, Session>,
+    routes: HashMap<SocketAddr, Routes>,
+    pending: HashMap<KademliaID, PendingHandshake>,
+    created: VecDeque<(Instant, KademliaID)>,
+}
+
+impl Sessions {
+    pub fn has_session(&self, addr: &SocketAddr, recipient: Option<KademliaID>) -> bool {
+        self.route(addr, recipient).is_some()
+    }
+
+    // the handshake to send to addr, the receiver fires once it is answered
+    // by expected, or by anyone when we do not know who is there
+    pub fn begin(
+        &mut self,
+        signing_key: &SigningKey,
+        addr: SocketAddr,
+        expected: Option<KademliaID>,
+    ) -> (Handshake, oneshot::Receiver<()>) {
+        let session_id = KademliaID::new();
+        let secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        let handshake = Handshake::new(signing_key, session_id, false, &secret, None);
+        let (done, rx) = oneshot::channel();
+        self.pending.insert(
+            session_id,
+            PendingHandshake {
+                secret,
+                addr,
+                expected,
+                done,
+            },
+        );
+        (handshake, rx)
+    }
+
+    pub fn abandon(&mut self, session_id: &KademliaID) {
+        self.pending.remove(session_id);
+    }
+
+    // handles a handshake from src, returns the reply to send if it was a
+    // new one and None when it finished one of ours or was not valid
+    pub fn accept(
+        &mut self,
+        signing_key: &SigningKey,
+        handshake: &Handshake,
+        src: SocketAddr,
+    ) -> Option<Handshake> {
+        if handshake.reply {
+            let pending = self.pending.get(&handshake.session_id)?;
+            let sent = PublicKey::from(&pending.secret).to_bytes();
+            if pending.addr != src {
+                return None;
+            }
+            let peer_id = handshake.verify(Some(&sent))?;
+            // someone else answering in its place leaves the handshake open
+            if pending.expected.is_some_and(|expected| expected != peer_id) {
+                println!(
+                    "Handshake with {} answered by {} instead",
+                    src,
+                    peer_id.to_hex()
+                );
+                return None;
+            }
+            let pending = self.pending.remove(&handshake.session_id)?;
+            let key = derive_key(&pending.secret, &handshake.ephemeral, &handshake.session_id);
+            self.insert(handshake.session_id, key, peer_id, src, true);
+            let _ = pending.done.send(());
+            None
+        } else {
+            let peer_id = handshake.verify(None)?;
+            if self.sessions.contains_key(&handshake.session_id) {
+                return None;
+            }
+            let secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+            let key = derive_key(&secret, &handshake.ephemeral, &handshake.session_id);
+            self.insert(handshake.session_id, key, peer_id, src, false);
+            Some(Handshake::new(
+                signing_key,
+                handshake.session_id,
+                true,
+                &secret,
+                Some(&handshake.ephemeral),
+            ))
+        }
+    }
+
+    // None when there is no session with recipient at addr
+    pub fn seal(
+        &mut self,
+        addr: &SocketAddr,
+        recipient: Option<KademliaID>,
+        plaintext: &[u8],
+    ) -> Option<Packet> {
+        let session_id = self.route(addr, recipient)?;
+        let session = self.sessions.get_mut(&session_id)?;
+        let nonce = nonce(session.initiator, session.sent);
+        session.sent += 1;
+        let ciphertext = session
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .ok()?;
+        Some(Packet::Sealed {
+            session_id,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    // the plaintext and the node id the session was set up with, None as
+    // well for a message that was already let in once
+    pub fn open(
+        &mut self,
+        src: SocketAddr,
+        session_id: &KademliaID,
+        nonce: &[u8; 12],
+        ciphertext: &[u8],
+    ) -> Option<(Vec<u8>, KademliaID)> {
+        let session = self
+            .sessions
+            .get_mut(session_id)
+            .filter(|session| session.created.elapsed() < SESSION_LIFETIME)?;
+        // the peer counts with the other direction byte
+        let counter = u64::from_be_bytes(nonce[4..].try_into().ok()?);
+        if *nonce != self::nonce(!session.initiator, counter) || !session.received.is_new(counter) {
+            return None;
+        }
+        let plaintext = session
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .ok()?;
+        session.received.mark(counter);
+        if !session.addrs.contains(&src) {
+            session.addrs.push(src);
+        }
+        let peer_id = session.peer_id;
+        self.add_route(src, peer_id, *session_id);
+        Some((plaintext, peer_id))
+    }
+
+    // the next request to addr sets up a new session
+    pub fn forget(&mut self, addr: &SocketAddr) {
+        self.routes.remove(addr);
+    }
+
+    fn route(&self, addr: &SocketAddr, recipient: Option<KademliaID>) -> Option<KademliaID> {
+        let routes = self.routes.get(addr)?;
+        let session_id = match recipient {
+            Some(recipient) => routes.by_id.get(&recipient)?,
+            None => routes.ours.as_ref()?,
+        };
+        self.is_live(session_id).then_some(*session_id)
+    }
+
+    fn is_live(&self, session_id: &KademliaID) -> bool {
+        self.sessions
+            .get(session_id)
+            .is_some_and(|session| session.created.elapsed() < SESSION_LIFETIME)
+    }
+
+    // a session the peer set up does not replace a live one we set up with
+    // it, else anyone could take over our traffic to an address
+    fn add_route(&mut self, addr: SocketAddr, peer_id: KademliaID, session_id: KademliaID) {
+        let initiator = self
+            .sessions
+            .get(&session_id)
+            .is_some_and(|session| session.initiator);
+        let current = self
+            .routes
+            .get(&addr)
+            .and_then(|routes| routes.by_id.get(&peer_id))
+            .copied();
+        let keep_current = !initiator
+            && current.is_some_and(|current| {
+                self.is_live(&current)
+                    && self
+                        .sessions
+                        .get(&current)
+                        .is_some_and(|session| session.initiator)
+            });
+        let routes = self.routes.entry(addr).or_default();
+        if !keep_current {
+            routes.by_id.insert(peer_id, session_id);
+        }
+        if initiator {
+            routes.ours = Some(session_id);
+        }
+    }
+
+    fn insert(
+        &mut self,
+        session_id: KademliaID,
+        key: Key,
+        peer_id: KademliaID,
+        addr: SocketAddr,
+        initiator: bool,
+    ) {
+        let now = Instant::now();
+        while let Some(&(created, oldest)) = self.created.front() {
+            if now.duration_since(created) < SESSION_LIFETIME && self.sessions.len() < MAX_SESSIONS
+            {
+                break;
+            }
+            self.created.pop_front();
+            self.remove(&oldest);
+        }
+
+        self.sessions.insert(
+            session_id,
+            Session {
+                cipher: ChaCha20Poly1305::new(&key),
+                peer_id,
+                created: now,
+                initiator,
+                sent: 0,
+                received: ReplayWindow::default(),
+                addrs: vec![addr],
+            },
+        );
+        self.created.push_back((now, session_id));
+        self.add_route(addr, peer_id, session_id);
+    }
+
+    fn remove(&mut self, session_id: &KademliaID) {
+        let Some(session) = self.sessions.remove(session_id) else {
+            return;
+        };
+        for addr in session.addrs {
+            let Some(routes) = self.routes.get_mut(&addr) else {
+                continue;
+            };
+            if routes.by_id.get(&session.peer_id) == Some(session_id) {
+                routes.by_id.remove(&session.peer_id);
+            }
+            if routes.ours == Some(*session_id) {
+                routes.ours = None;
+            }
+            if routes.by_id.is_empty() && routes.ours.is_none() {
+                self.routes.remove(&addr);
+            }
+        }
+    }
+}
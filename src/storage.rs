@@ -0,0 +1,138 @@
+use {
+    crate::{kademlia_id::KademliaID, record::MutableRecord},
+    std::{
+        io::{Error, ErrorKind},
+        path::{Path, PathBuf},
+    },
+    tokio::{fs, sync::Mutex},
+};
+
+// STOREs and DELETEs are handled in parallel, checking what is on disk and
+// writing after it has to happen under the lock of the key
+const KEY_LOCKS: usize = 64;
+static LOCKS: [Mutex<()>; KEY_LOCKS] = [const { Mutex::const_new(()) }; KEY_LOCKS];
+
+fn key_lock(key: &KademliaID) -> &'static Mutex<()> {
+    &LOCKS[key.id[0] as usize % KEY_LOCKS]
+}
+
+pub fn value_path(storage_dir: &Path, key: &KademliaID) -> PathBuf {
+    storage_dir.join(format!("{}.txt", key.to_hex()))
+}
+
+fn publisher_path(storage_dir: &Path, key: &KademliaID) -> PathBuf {
+    storage_dir.join(format!("{}.publisher", key.to_hex()))
+}
+
+// plain values are stored under their hash, the first publisher of a key is
+// kept and only it may delete the value later, records belong to the key that
+// signed them no matter who stored them
+pub async fn store_value(
+    storage_dir: &Path,
+    key: &KademliaID,
+    data: &str,
+    publisher: Option<KademliaID>,
+) -> std::io::Result<()> {
+    let _guard = key_lock(key).lock().await;
+    let is_record = MutableRecord::is_record(data);
+    if is_record {
+        check_record(storage_dir, key, data).await?;
+    } else if KademliaID::from_data(data) != *key {
+        // a replica of the real value must not be overwritten with garbage
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("value does not hash to {}", key.to_hex()),
+        ));
+    }
+
+    fs::create_dir_all(storage_dir).await?;
+    // readers never see half a value
+    let path = value_path(storage_dir, key);
+    let partial = path.with_extension("partial");
+    fs::write(&partial, data).await?;
+    fs::rename(&partial, &path).await?;
+
+    if let Some(publisher) = publisher.filter(|_| !is_record) {
+        let path = publisher_path(storage_dir, key);
+        if fs::metadata(&path).await.is_err() {
+            fs::write(&path, publisher.to_hex()).await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn load_publisher(
+    storage_dir: &Path,
+    key: &KademliaID,
+) -> std::io::Result<Option<KademliaID>> {
+    match fs::read_to_string(publisher_path(storage_dir, key)).await {
+        Ok(hex) => Ok(KademliaID::try_from_hex(hex.trim())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Ok(false) when there was nothing stored under the key
+pub async fn delete_value(
+    storage_dir: &Path,
+    key: &KademliaID,
+    requester: &KademliaID,
+) -> std::io::Result<bool> {
+    let _guard = key_lock(key).lock().await;
+    let path = value_path(storage_dir, key);
+    let stored = match fs::read_to_string(&path).await {
+        Ok(stored) => stored,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+
+    let owner = match MutableRecord::decode(&stored) {
+        Some(record) => Some(record.key()),
+        None => load_publisher(storage_dir, key).await?,
+    };
+    if owner != Some(*requester) {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "{} is not the publisher of {}",
+                requester.to_hex(),
+                key.to_hex()
+            ),
+        ));
+    }
+
+    fs::remove_file(&path).await?;
+    match fs::remove_file(publisher_path(storage_dir, key)).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    Ok(true)
+}
+
+// a record has to be signed by the key it is stored under and may only
+// replace an older sequence
+async fn check_record(storage_dir: &Path, key: &KademliaID, data: &str) -> std::io::Result<()> {
+    let record = MutableRecord::decode(data)
+        .filter(|record| record.key() == *key && record.verify())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid record signature"))?;
+
+    let stored = match fs::read_to_string(value_path(storage_dir, key)).await {
+        Ok(stored) => MutableRecord::decode(&stored),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    if let Some(stored) = stored {
+        if stored.sequence > record.sequence
+            || (stored.sequence == record.sequence && stored != record)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "stale sequence {}, already have {}",
+                    record.sequence, stored.sequence
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
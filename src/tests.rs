@@ -1,38 +1,54 @@
-use crate::{
-    contact::Contact, kademlia::Kademlia, kademlia_id::KademliaID,
-    routing_table_handler::RouteTableCMD,
-};
-
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     use std::cmp::Ordering;
+    use std::net::{IpAddr, SocketAddr};
     use std::sync::Arc;
     use std::time::Duration;
 
     use crate::bucket::Bucket;
     use crate::cli::{CMDStatus, Cli, Command};
+    use crate::config::{Config, KademliaConfig};
     use crate::constants::{rpc::Command as otherCommand, BUCKET_SIZE, ID_LENGTH, RT_BCKT_SIZE};
     use crate::contact::Contact;
     use crate::kademlia::Kademlia;
     use crate::kademlia_id::KademliaID;
+    use crate::limits::{DiversityLimits, RateLimiter, StoreQuota};
+    use crate::lookup::{FindNode, FindValue, Lookup};
+    use crate::nat::AddressDiscovery;
     use crate::networking::Networking;
+    use crate::node_state;
+    use crate::puzzle;
+    use crate::record::MutableRecord;
     use crate::routing_table::RoutingTable;
     use crate::routing_table_handler::{routing_table_handler, RouteTableCMD};
-    use crate::rpc::RpcMessage;
+    use crate::rpc::{Envelope, RpcMessage, SeenRequests, SignedRpc};
+    use crate::session::{Packet, Sessions};
+    use crate::storage;
+    use crate::utils;
+    use ed25519_dalek::SigningKey;
+    use tokio::io::AsyncReadExt;
     use tokio::net::UdpSocket;
     use tokio::sync::{broadcast, mpsc};
     use tokio::time::sleep;
+    fn test_config() -> KademliaConfig {
+        KademliaConfig {
+            rpc_timeout: Duration::from_millis(500),
+            ..KademliaConfig::default()
+        }
+    }
+
     #[test]
     fn test_contact_placed_in_correct_bucket() {
         let my_id = KademliaID::new();
-        let me = Contact::new(my_id.clone(), "127.0.0.1".to_string());
+        let me = Contact::new(my_id, "127.0.0.1:5678".parse().unwrap());
         let mut routing_table = RoutingTable::new(me.clone());
 
         for i in 0..BUCKET_SIZE {
             let contact_id = my_id.generate_random_id_in_bucket(i);
-            let contact = Contact::new(contact_id.clone(), format!("127.0.0.{}", i));
+            let contact = Contact::new(contact_id, format!("127.0.0.{}:5678", i).parse().unwrap());
 
-            let expected_bucket_index = routing_table.get_bucket_index(contact_id.clone());
+            let expected_bucket_index = routing_table.get_bucket_index(contact_id);
 
             routing_table.add_contact(contact.clone());
 
@@ -50,10 +66,10 @@ mod tests {
         let mut bucket = Bucket::new();
         let target_id = KademliaID::new();
         let contact_id = target_id.generate_random_id_in_bucket(0);
-        let contact = Contact::new(contact_id.clone(), "127.0.0.1".to_string());
+        let contact = Contact::new(contact_id, "127.0.0.1:5678".parse().unwrap());
 
-        bucket.add_contact(&contact, target_id.clone());
-        bucket.add_contact(&contact, target_id.clone());
+        bucket.add_contact(&contact, target_id);
+        bucket.add_contact(&contact, target_id);
 
         assert_eq!(
             bucket.len(),
@@ -67,20 +83,20 @@ mod tests {
         let mut bucket = Bucket::new();
         let target_id = KademliaID::new();
 
-        let contacts = bucket.get_contact_and_calc_distance(target_id.clone());
+        let contacts = bucket.get_contact_and_calc_distance(target_id);
         assert_eq!(contacts.len(), 0, "Expected empty bucket but got contacts");
     }
 
     #[test]
     fn test_routing_table_bucket_indexing() {
         let my_id = KademliaID::new();
-        let me = Contact::new(my_id.clone(), "127.0.0.1".to_string());
+        let me = Contact::new(my_id, "127.0.0.1:5678".parse().unwrap());
         let mut routing_table = RoutingTable::new(me.clone());
 
         for i in 0..BUCKET_SIZE {
             let contact_id = my_id.generate_random_id_in_bucket(i);
-            let contact = Contact::new(contact_id.clone(), format!("127.0.0.{}", i));
-            let bucket_index = routing_table.get_bucket_index(contact_id.clone());
+            let contact = Contact::new(contact_id, format!("127.0.0.{}:5678", i).parse().unwrap());
+            let bucket_index = routing_table.get_bucket_index(contact_id);
             assert!(bucket_index < RT_BCKT_SIZE, "Bucket index out of bounds");
 
             routing_table.add_contact(contact.clone());
@@ -116,17 +132,17 @@ mod tests {
     #[test]
     fn test_full_routing_table() {
         let my_id = KademliaID::new();
-        let me = Contact::new(my_id.clone(), "127.0.0.1".to_string());
+        let me = Contact::new(my_id, "127.0.0.1:5678".parse().unwrap());
         let mut routing_table = RoutingTable::new(me.clone());
 
         for i in 0..(BUCKET_SIZE * 2) {
             let contact_id = my_id.generate_random_id_in_bucket(i);
-            let contact = Contact::new(contact_id.clone(), format!("127.0.0.{}", i));
+            let contact = Contact::new(contact_id, format!("127.0.0.{}:5678", i).parse().unwrap());
             routing_table.add_contact(contact);
         }
 
         let target_id = my_id.generate_random_id_in_bucket(1);
-        let closest_contacts = routing_table.find_closest_contacts(target_id.clone(), BUCKET_SIZE);
+        let closest_contacts = routing_table.find_closest_contacts(target_id, BUCKET_SIZE);
 
         assert!(
             !closest_contacts.is_empty(),
@@ -154,7 +170,7 @@ mod tests {
     #[test]
     fn test_find_closest_contacts() {
         let my_id = KademliaID::from_hex("0000000000000000000000000000000000000000".to_string());
-        let me = Contact::new(my_id.clone(), "1256".to_string());
+        let me = Contact::new(my_id, "127.0.0.1:1256".parse().unwrap());
 
         let mut routing_table = RoutingTable::new(me);
 
@@ -165,7 +181,7 @@ mod tests {
             println!("Generated KademliaID: {}", hex_value);
 
             let kad_id = KademliaID::from_hex(hex_value.clone());
-            let contact = Contact::new(kad_id.clone(), "123".to_string());
+            let contact = Contact::new(kad_id, "127.0.0.1:123".parse().unwrap());
 
             routing_table.add_contact(contact);
             println!(
@@ -177,7 +193,7 @@ mod tests {
         let target_id =
             KademliaID::from_hex("FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF".to_string());
 
-        let closest_contacts = routing_table.find_closest_contacts(target_id.clone(), 20);
+        let closest_contacts = routing_table.find_closest_contacts(target_id, 20);
 
         assert_eq!(
             closest_contacts.len(),
@@ -208,7 +224,7 @@ mod tests {
 
         for i in 0..(BUCKET_SIZE + 5) {
             let contact_id = target_id.generate_random_id_in_bucket(i);
-            let contact = Contact::new(contact_id, format!("address{}", i));
+            let contact = Contact::new(contact_id, format!("127.0.0.{}:5678", i).parse().unwrap());
             bucket.add_contact(&contact, target_id);
         }
 
@@ -260,7 +276,7 @@ mod tests {
     #[tokio::test]
     async fn test_routing_table_handler() {
         let local_id = KademliaID::new();
-        let local_contact = Contact::new(local_id.clone(), "127.0.0.1:8080".to_string());
+        let local_contact = Contact::new(local_id, "127.0.0.1:8080".parse().unwrap());
         let routing_table = RoutingTable::new(local_contact.clone());
 
         let (tx, rx) = mpsc::channel::<RouteTableCMD>(32);
@@ -270,7 +286,7 @@ mod tests {
         });
 
         let contact_id = KademliaID::new();
-        let contact = Contact::new(contact_id.clone(), "127.0.0.1:8081".to_string());
+        let contact = Contact::new(contact_id, "127.0.0.1:8081".parse().unwrap());
 
         tx.send(RouteTableCMD::AddContact(contact.clone()))
             .await
@@ -279,7 +295,7 @@ mod tests {
         tokio::time::sleep(std::time::Duration::from_millis(50)).await;
 
         let (reply_tx, mut reply_rx) = mpsc::channel(1);
-        tx.send(RouteTableCMD::GetBucketIndex(contact_id.clone(), reply_tx))
+        tx.send(RouteTableCMD::GetBucketIndex(contact_id, reply_tx))
             .await
             .unwrap();
 
@@ -292,7 +308,7 @@ mod tests {
         );
 
         let (reply_tx, mut reply_rx) = mpsc::channel(1);
-        tx.send(RouteTableCMD::GetClosestNodes(contact_id.clone(), reply_tx))
+        tx.send(RouteTableCMD::GetClosestNodes(contact_id, reply_tx))
             .await
             .unwrap();
 
@@ -313,9 +329,9 @@ mod tests {
     #[test]
     fn test_contact_from_hex() {
         let hex_id = "0123456789abcdef0123456789abcdef01234567".to_string();
-        let address = "127.0.0.1:8080".to_string();
+        let address = "127.0.0.1:8080".parse().unwrap();
 
-        let contact = Contact::contact_from_hex(hex_id.clone(), address.clone());
+        let contact = Contact::contact_from_hex(hex_id.clone(), address);
 
         assert_eq!(contact.id.to_hex(), hex_id, "Contact ID does not match");
         assert_eq!(contact.address, address, "Contact address does not match");
@@ -325,10 +341,10 @@ mod tests {
     fn test_contact_less() {
         let target_id = KademliaID::new();
 
-        let mut contact1 = Contact::new(KademliaID::new(), "127.0.0.1:8081".to_string());
+        let mut contact1 = Contact::new(KademliaID::new(), "127.0.0.1:8081".parse().unwrap());
         contact1.calc_distance(&target_id);
 
-        let mut contact2 = Contact::new(KademliaID::new(), "127.0.0.1:8082".to_string());
+        let mut contact2 = Contact::new(KademliaID::new(), "127.0.0.1:8082".parse().unwrap());
         contact2.calc_distance(&target_id);
 
         let less = contact1.less(contact2.clone());
@@ -411,21 +427,31 @@ mod tests {
 
     #[tokio::test]
     async fn test_kademlia_new() {
-        let kademlia = Kademlia::new();
+        let kademlia = Kademlia::new(test_config());
         assert!(kademlia.route_table_tx.capacity() > 0);
         assert_eq!(kademlia.own_id.id.len(), ID_LENGTH);
     }
 
     #[tokio::test]
     async fn test_kademlia_join() {
-        let kademlia = Kademlia::new();
+        let kademlia = Kademlia::new(test_config());
         let result = kademlia.join().await;
         assert!(result.is_ok(), "Kademlia join failed: {:?}", result.err());
     }
 
+    #[tokio::test]
+    async fn test_kademlia_join_without_boot_nodes() {
+        let kademlia = Kademlia::new(test_config());
+        let result = kademlia.join_via(&[]).await;
+        assert!(
+            result.is_err(),
+            "Join without boot nodes should fail instead of panicking"
+        );
+    }
+
     #[tokio::test]
     async fn test_iterative_find_node() {
-        let kademlia = Kademlia::new();
+        let kademlia = Kademlia::new(test_config());
         let target_id = KademliaID::new();
 
         let result = kademlia.iterative_find_node(target_id).await;
@@ -434,13 +460,13 @@ mod tests {
             "iterative_find_node failed: {:?}",
             result.err()
         );
-        let contacts = result.unwrap();
-        assert!(contacts.len() >= 0, "Expected contacts");
+        let contacts = result.unwrap().result;
+        assert!(contacts.len() <= BUCKET_SIZE, "Expected at most k contacts");
     }
 
     #[tokio::test]
     async fn test_parse_command() {
-        let kademlia = Arc::new(Kademlia::new());
+        let kademlia = Arc::new(Kademlia::new(test_config()));
         let (shutdown_tx, _) = broadcast::channel(1);
         let cli = Cli::new(kademlia.clone(), shutdown_tx.clone());
 
@@ -455,35 +481,35 @@ mod tests {
 
     #[tokio::test]
     async fn test_execute_command_exit() {
-        let kademlia = Arc::new(Kademlia::new());
+        let kademlia = Arc::new(Kademlia::new(test_config()));
         let (shutdown_tx, _) = broadcast::channel(1);
         let cli = Cli::new(kademlia.clone(), shutdown_tx.clone());
 
         let status = cli.execute_command(Command::EXIT).await;
         match status {
-            CMDStatus::EXIT => assert!(true),
-            _ => assert!(false, "Expected CMDStatus::EXIT"),
+            CMDStatus::EXIT => {}
+            _ => panic!("Expected CMDStatus::EXIT"),
         }
     }
 
     #[tokio::test]
     async fn test_iterative_find_node_with_empty_routing_table() {
-        let kademlia = Kademlia::new();
+        let kademlia = Kademlia::new(test_config());
         let target_id = KademliaID::new();
 
         let result = kademlia.iterative_find_node(target_id).await;
 
         assert!(result.is_ok(), "Expected Ok, but got an error");
-        let contacts = result.unwrap();
+        let contacts = result.unwrap().result;
         assert!(contacts.is_empty(), "Expected no contacts, but got some");
     }
 
     #[tokio::test]
     async fn test_iterative_find_node_with_network_failure() {
-        let kademlia = Kademlia::new();
+        let kademlia = Kademlia::new(test_config());
         let target_id = KademliaID::new();
         let contact_id = KademliaID::new();
-        let contact = Contact::new(contact_id.clone(), "127.0.0.1:8080".to_string());
+        let contact = Contact::new(contact_id, "127.0.0.1:8080".parse().unwrap());
 
         kademlia
             .route_table_tx
@@ -495,7 +521,7 @@ mod tests {
         let result = kademlia.iterative_find_node(target_id).await;
 
         assert!(result.is_ok(), "Expected Ok, but got an error");
-        let contacts = result.unwrap();
+        let contacts = result.unwrap().result;
         assert!(
             contacts.is_empty(),
             "Expected no new contacts after failure"
@@ -504,19 +530,19 @@ mod tests {
 
     #[tokio::test]
     async fn test_iterative_find_value_with_empty_routing_table() {
-        let kademlia = Kademlia::new();
+        let kademlia = Kademlia::new(test_config());
         let target_id = KademliaID::new();
 
         let result = kademlia.iterative_find_value(target_id).await;
 
         assert!(result.is_ok(), "Expected Ok, but got an error");
-        let value = result.unwrap();
+        let value = result.unwrap().result;
         assert!(value.is_none(), "Expected no value, but found some data");
     }
 
     #[tokio::test]
     async fn test_iterative_find_value() {
-        let kademlia = Kademlia::new();
+        let kademlia = Kademlia::new(test_config());
         let target_id = KademliaID::new();
         let result = kademlia.iterative_find_value(target_id).await;
         assert!(
@@ -528,25 +554,28 @@ mod tests {
 
     #[tokio::test]
     async fn test_iterative_store_no_contacts() {
-        let kademlia = Kademlia::new();
+        let kademlia = Kademlia::new(test_config());
         let target_id = KademliaID::new();
-        let mock_closest_nodes: Vec<Contact> = vec![];
+        let _mock_closest_nodes: Vec<Contact> = vec![];
 
         let kademlia_clone = kademlia.clone();
         let result = kademlia_clone
             .iterative_store(target_id, "test data".to_string())
             .await;
 
-        assert!(result.is_ok(), "Expected Ok, but got an error");
+        assert!(
+            result.is_err(),
+            "Storing on no nodes should not reach the quorum"
+        );
     }
 
     #[tokio::test]
     async fn test_iterative_store() {
-        let kademlia = Kademlia::new();
+        let kademlia = Kademlia::new(test_config());
         let target_id = KademliaID::new();
         let data = "test data".to_string();
         let result = kademlia.iterative_store(target_id, data).await;
-        assert!(result.is_ok(), "iterative_store failed: {:?}", result.err());
+        assert!(result.is_err(), "Expected an error without any replicas");
     }
     #[tokio::test]
     async fn test_send_rpc_request() {
@@ -559,8 +588,12 @@ mod tests {
             let mut buf = [0u8; 65507];
             let (len, _src) = server_socket.recv_from(&mut buf).await.unwrap();
 
-            let received_msg: RpcMessage =
-                bincode::deserialize(&buf[..len]).expect("Failed to deserialize data");
+            let Packet::Plain(signed) =
+                bincode::deserialize(&buf[..len]).expect("Failed to deserialize data")
+            else {
+                panic!("Expected an unencrypted packet");
+            };
+            let (_sender_id, received_msg) = signed.open().expect("Invalid signature");
 
             msg_tx.send(received_msg).await.unwrap();
         });
@@ -571,14 +604,7 @@ mod tests {
         let target_addr = server_addr.to_string();
 
         let result = networking
-            .send_rpc_request(
-                rpc_id.clone(),
-                &target_addr,
-                otherCommand::PING,
-                None,
-                None,
-                None,
-            )
+            .send_rpc_request(rpc_id, &target_addr, otherCommand::PING, None, None, None)
             .await;
 
         assert!(
@@ -609,18 +635,12 @@ mod tests {
     }
     #[tokio::test]
     async fn test_rpc_timeout() {
-        let networking = Networking::new();
+        let networking =
+            Networking::with_config(&test_config(), SigningKey::generate(&mut rand::rngs::OsRng));
         let rpc_id = KademliaID::new();
         let target_addr = "127.0.0.1:12345";
         let result = networking
-            .send_rpc_request_await(
-                rpc_id.clone(),
-                target_addr,
-                otherCommand::PING,
-                None,
-                None,
-                None,
-            )
+            .send_rpc_request_await(rpc_id, target_addr, otherCommand::PING, None, None, None)
             .await;
         assert!(result.is_ok(), "Request should not fail");
         assert!(
@@ -633,7 +653,7 @@ mod tests {
     fn test_calc_distance() {
         let id1 = KademliaID::new();
         let id2 = KademliaID::new();
-        let mut contact = Contact::new(id1.clone(), "127.0.0.1:8080".to_string());
+        let mut contact = Contact::new(id1, "127.0.0.1:8080".parse().unwrap());
 
         contact.calc_distance(&id2);
         let expected_distance = id1.distance(&id2);
@@ -648,7 +668,7 @@ mod tests {
     #[test]
     fn test_calc_distance_same_id() {
         let id = KademliaID::new();
-        let mut contact = Contact::new(id.clone(), "127.0.0.1:8080".to_string());
+        let mut contact = Contact::new(id, "127.0.0.1:8080".parse().unwrap());
 
         contact.calc_distance(&id);
         let expected_distance = KademliaID::with_id([0u8; 20]);
@@ -659,4 +679,2129 @@ mod tests {
             "Distance should be zero for the same ID"
         );
     }
+
+    #[tokio::test]
+    async fn test_node_key_persists_across_restarts() {
+        let state_dir =
+            std::env::temp_dir().join(format!("kadrustlia-state-{}", KademliaID::new().to_hex()));
+
+        let first = node_state::load_or_create_node_key(&state_dir, false, 0)
+            .await
+            .unwrap();
+        let second = node_state::load_or_create_node_key(&state_dir, false, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            first.to_bytes(),
+            second.to_bytes(),
+            "Node key should be reloaded from disk"
+        );
+
+        let regenerated = node_state::load_or_create_node_key(&state_dir, true, 0)
+            .await
+            .unwrap();
+        assert_ne!(
+            first.to_bytes(),
+            regenerated.to_bytes(),
+            "Regeneration should produce a new key"
+        );
+
+        let reloaded = node_state::load_or_create_node_key(&state_dir, false, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            regenerated.to_bytes(),
+            reloaded.to_bytes(),
+            "Regenerated key should replace the old one"
+        );
+
+        let _ = tokio::fs::remove_dir_all(&state_dir).await;
+    }
+
+    #[test]
+    fn test_kademlia_id_try_from_hex() {
+        let hex = "0123456789abcdef0123456789abcdef01234567";
+        let kad_id = KademliaID::try_from_hex(hex).expect("valid hex should parse");
+        assert_eq!(kad_id.to_hex(), hex);
+
+        assert!(KademliaID::try_from_hex("0123").is_none());
+        assert!(KademliaID::try_from_hex("zz23456789abcdef0123456789abcdef01234567").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_routing_table_save_and_load() {
+        let state_dir =
+            std::env::temp_dir().join(format!("kadrustlia-state-{}", KademliaID::new().to_hex()));
+        let kademlia = Kademlia::new(test_config());
+
+        let loaded = node_state::load_routing_table(&state_dir).await.unwrap();
+        assert!(loaded.is_empty(), "Missing file should load as empty table");
+
+        for i in 0..5 {
+            let contact_id = kademlia.own_id.generate_random_id_in_bucket(i);
+            let contact = Contact::new(contact_id, format!("127.0.0.{}:5678", i).parse().unwrap());
+            kademlia
+                .route_table_tx
+                .send(RouteTableCMD::AddContact(contact))
+                .await
+                .unwrap();
+        }
+
+        kademlia.save_routing_table(&state_dir).await.unwrap();
+        let loaded = node_state::load_routing_table(&state_dir).await.unwrap();
+
+        assert_eq!(loaded.len(), 5, "Expected all contacts to be persisted");
+        for i in 0..5 {
+            assert!(
+                loaded
+                    .iter()
+                    .any(|c| c.address == format!("127.0.0.{}:5678", i).parse().unwrap()),
+                "Contact 127.0.0.{} missing after reload",
+                i
+            );
+        }
+
+        let _ = tokio::fs::remove_dir_all(&state_dir).await;
+    }
+
+    #[test]
+    fn test_parse_boot_node_addresses() {
+        let addresses = utils::parse_boot_node_addresses(
+            " bootNode, 10.0.0.2:6000 ,,seed.local, fd00::2, [fd00::3]:6000",
+        );
+        assert_eq!(
+            addresses,
+            vec![
+                "bootNode:5678".to_string(),
+                "10.0.0.2:6000".to_string(),
+                "seed.local:5678".to_string(),
+                "[fd00::2]:5678".to_string(),
+                "[fd00::3]:6000".to_string()
+            ]
+        );
+        assert!(utils::parse_boot_node_addresses("").is_empty());
+    }
+
+    fn free_udp_port() -> u16 {
+        std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn free_local_addr() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], free_udp_port()))
+    }
+
+    // a spawned node that takes its storage directory with it when dropped
+    struct LocalNode {
+        node: Arc<Kademlia>,
+    }
+
+    impl std::ops::Deref for LocalNode {
+        type Target = Arc<Kademlia>;
+
+        fn deref(&self) -> &Arc<Kademlia> {
+            &self.node
+        }
+    }
+
+    impl Drop for LocalNode {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.node.config.storage_dir);
+        }
+    }
+
+    async fn spawn_local_node(boot_nodes: Vec<String>, k: usize) -> LocalNode {
+        let config = KademliaConfig {
+            advertise_address: free_local_addr(),
+            boot_nodes,
+            k,
+            rpc_timeout: Duration::from_secs(2),
+            stall_timeout: Duration::from_millis(200),
+            // every node gets its own values, a shared directory lets a node
+            // find on its own disk what it was supposed to look up
+            storage_dir: std::env::temp_dir()
+                .join(format!("kadrustlia-data-{}", KademliaID::new().to_hex())),
+            ..KademliaConfig::default()
+        };
+        LocalNode {
+            node: spawn_configured_node(config).await,
+        }
+    }
+
+    async fn spawn_configured_node(config: KademliaConfig) -> Arc<Kademlia> {
+        let addr = config.advertise_address;
+        let kademlia = Arc::new(Kademlia::with_config(
+            SigningKey::generate(&mut rand::rngs::OsRng),
+            config,
+        ));
+        kademlia.bind(addr).await.unwrap();
+
+        let kademlia_listen = Arc::clone(&kademlia);
+        tokio::spawn(async move {
+            kademlia_listen.listen(addr).await;
+        });
+        kademlia
+    }
+
+    #[tokio::test]
+    async fn test_join_through_local_boot_node() {
+        let boot_node = spawn_local_node(Vec::new(), 20).await;
+        let node = spawn_local_node(vec![boot_node.config.advertise_address.to_string()], 20).await;
+
+        let result = node.join().await;
+        assert!(result.is_ok(), "Join failed: {:?}", result.err());
+
+        let (reply_tx, mut reply_rx) = mpsc::channel(1);
+        boot_node
+            .route_table_tx
+            .send(RouteTableCMD::GetClosestNodes(node.own_id, reply_tx))
+            .await
+            .unwrap();
+        let contacts = reply_rx.recv().await.unwrap();
+        assert!(
+            contacts
+                .iter()
+                .any(|c| c.id == node.own_id && c.address == node.config.advertise_address),
+            "Boot node should know the joining node by its advertised address"
+        );
+
+        let (reply_tx, mut reply_rx) = mpsc::channel(1);
+        node.route_table_tx
+            .send(RouteTableCMD::GetAllContacts(reply_tx))
+            .await
+            .unwrap();
+        let contacts = reply_rx.recv().await.unwrap();
+        assert!(
+            contacts.iter().any(|c| c.id == boot_node.own_id),
+            "The PONG should add the boot node under its own id"
+        );
+    }
+
+    #[test]
+    fn test_config_precedence() {
+        let dir =
+            std::env::temp_dir().join(format!("kadrustlia-cfg-{}", KademliaID::new().to_hex()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("kadrustlia.toml");
+        std::fs::write(
+            &path,
+            "port = 6000\nk = 8\nbootstrap = [\"bootNode\"]\nstorage_dir = \"/tmp/values\"\n",
+        )
+        .unwrap();
+
+        let args: Vec<String> = vec![
+            "--config".to_string(),
+            path.display().to_string(),
+            "--alpha".to_string(),
+            "5".to_string(),
+            "--regenerate-id".to_string(),
+        ];
+        let config = Config::from_sources(&args, |name| match name {
+            "KAD_PORT" => Some("7000".to_string()),
+            "KAD_ALPHA" => Some("4".to_string()),
+            _ => None,
+        })
+        .unwrap();
+
+        assert_eq!(config.port, 7000, "env should override the config file");
+        assert_eq!(config.alpha, 5, "command line should override env");
+        assert_eq!(config.k, 8);
+        assert!(config.regenerate_id);
+        assert_eq!(config.bootstrap, vec!["bootNode:5678".to_string()]);
+        assert_eq!(config.storage_dir, std::path::PathBuf::from("/tmp/values"));
+        assert_eq!(config.http_port, 3000);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_config_rejects_invalid_values() {
+        let bad_key = vec!["--node-key".to_string(), "1234".to_string()];
+        assert!(Config::from_sources(&bad_key, |_| None).is_err());
+
+        let unknown = vec!["--no-such-option".to_string(), "1".to_string()];
+        assert!(Config::from_sources(&unknown, |_| None).is_err());
+
+        let missing_value = vec!["--port".to_string()];
+        assert!(Config::from_sources(&missing_value, |_| None).is_err());
+
+        assert!(Config::from_toml("unknown_key = 1").is_err());
+
+        let quorum_above_k = vec!["--k=2".to_string(), "--store-quorum=3".to_string()];
+        assert!(Config::from_sources(&quorum_above_k, |_| None).is_err());
+
+        let no_paths = vec!["--disjoint-paths=0".to_string()];
+        assert!(Config::from_sources(&no_paths, |_| None).is_err());
+    }
+
+    #[test]
+    fn test_config_kademlia_config() {
+        let args: Vec<String> = vec![
+            "--advertise-address=10.0.0.7".to_string(),
+            "--port=6001".to_string(),
+            "--rpc-timeout-ms=250".to_string(),
+        ];
+        let kademlia_config = Config::from_sources(&args, |_| None)
+            .unwrap()
+            .kademlia_config();
+
+        assert_eq!(
+            kademlia_config.advertise_address.to_string(),
+            "10.0.0.7:6001"
+        );
+        assert_eq!(kademlia_config.rpc_timeout, Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_bucket_respects_configured_size() {
+        let mut bucket = Bucket::with_size(4);
+        let target_id = KademliaID::new();
+
+        for i in 0..10 {
+            let contact_id = target_id.generate_random_id_in_bucket(i);
+            bucket.add_contact(
+                &Contact::new(contact_id, format!("127.0.0.{}:5678", i).parse().unwrap()),
+                target_id,
+            );
+        }
+
+        assert_eq!(
+            bucket.len(),
+            4,
+            "Bucket should be capped at its configured k"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_small_k_limits_closest_nodes() {
+        let config = KademliaConfig {
+            k: 3,
+            ..test_config()
+        };
+        let kademlia = Kademlia::new(config);
+
+        for i in 0..10 {
+            let contact_id = kademlia.own_id.generate_random_id_in_bucket(i);
+            kademlia
+                .route_table_tx
+                .send(RouteTableCMD::AddContact(Contact::new(
+                    contact_id,
+                    format!("127.0.0.{}:5678", i).parse().unwrap(),
+                )))
+                .await
+                .unwrap();
+        }
+
+        let (reply_tx, mut reply_rx) = mpsc::channel(1);
+        kademlia
+            .route_table_tx
+            .send(RouteTableCMD::GetClosestNodes(KademliaID::new(), reply_tx))
+            .await
+            .unwrap();
+        let contacts = reply_rx.recv().await.unwrap();
+        assert_eq!(contacts.len(), 3, "Expected k closest nodes");
+    }
+
+    #[tokio::test]
+    async fn test_iterative_find_node_returns_k_closest_in_order() {
+        let k = 4;
+        let boot_node = spawn_local_node(Vec::new(), k).await;
+        let boot_addr = vec![boot_node.config.advertise_address.to_string()];
+
+        let mut nodes = vec![];
+        for _ in 0..8 {
+            let node = spawn_local_node(boot_addr.clone(), k).await;
+            node.join().await.unwrap();
+            nodes.push(node);
+        }
+
+        let target_id = KademliaID::new();
+        let contacts = nodes[0]
+            .iterative_find_node(target_id)
+            .await
+            .unwrap()
+            .result;
+
+        assert_eq!(contacts.len(), k, "Expected exactly k contacts");
+        assert!(
+            contacts.iter().all(|c| c.id != nodes[0].own_id),
+            "Lookup should not return the node itself"
+        );
+        for pair in contacts.windows(2) {
+            assert!(
+                pair[0].id.distance(&target_id) < pair[1].id.distance(&target_id),
+                "Contacts should be sorted by distance to the target"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lookup_does_not_wait_for_stalled_peers() {
+        let target_id = KademliaID::from_data("stalled value");
+        let holder = spawn_local_node(Vec::new(), 20).await;
+        let storage_dir = holder.config.storage_dir.clone();
+        tokio::fs::create_dir_all(&storage_dir).await.unwrap();
+        let value_file = storage_dir.join(format!("{}.txt", target_id.to_hex()));
+        tokio::fs::write(&value_file, "stalled value")
+            .await
+            .unwrap();
+
+        let node = spawn_local_node(Vec::new(), 20).await;
+
+        // peers closer to the target than the holder that never answer
+        let mut black_holes = vec![];
+        for i in 0..node.config.alpha {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut id = target_id;
+            id.id[ID_LENGTH - 1] ^= (i + 1) as u8;
+            let contact = Contact::new(id, socket.local_addr().unwrap());
+            node.route_table_tx
+                .send(RouteTableCMD::AddContact(contact))
+                .await
+                .unwrap();
+            black_holes.push(socket);
+        }
+        // farther from the target than the black holes, which only differ
+        // from it in the last byte
+        let holder_id = holder.own_id;
+        node.route_table_tx
+            .send(RouteTableCMD::AddContact(holder.own_contact()))
+            .await
+            .unwrap();
+
+        let started = std::time::Instant::now();
+        let report = node.iterative_find_value(target_id).await.unwrap();
+        let elapsed = started.elapsed();
+
+        assert_eq!(report.result, Some("stalled value".to_string()));
+        assert_eq!(report.value_from.map(|c| c.id), Some(holder_id));
+        assert_eq!(
+            report.timed_out.len(),
+            black_holes.len(),
+            "Stalled peers should be reported as timed out"
+        );
+        assert!(
+            elapsed < node.config.rpc_timeout,
+            "Lookup waited {:?} for stalled peers",
+            elapsed
+        );
+    }
+
+    fn lookup_response(data: Option<String>, contacts: Vec<Contact>) -> RpcMessage {
+        RpcMessage::Response {
+            rpc_id: KademliaID::new(),
+            result: otherCommand::FINDNODE,
+            data,
+            contact: Some(contacts),
+        }
+    }
+
+    fn contact_at_distance(target_id: KademliaID, distance: u8) -> Contact {
+        let mut id = target_id;
+        id.id[0] ^= distance;
+        Contact::new(
+            id,
+            SocketAddr::from(([127, 0, 0, 1], 6000 + distance as u16)),
+        )
+    }
+
+    #[test]
+    fn test_lookup_converges_on_k_closest() {
+        let target_id = KademliaID::new();
+        let config = KademliaConfig {
+            k: 3,
+            alpha: 2,
+            ..test_config()
+        };
+        let far: Vec<Contact> = (0..4)
+            .map(|i| contact_at_distance(target_id, 0x80 | i))
+            .collect();
+        let mut lookup = Lookup::new(FindNode, KademliaID::new(), target_id, &config, far);
+        let now = tokio::time::Instant::now();
+
+        let queries = lookup.next_queries(now);
+        assert_eq!(queries.len(), 2, "Only alpha queries should be in flight");
+        assert!(lookup.next_queries(now).is_empty());
+
+        let closer: Vec<Contact> = (1..4).map(|i| contact_at_distance(target_id, i)).collect();
+        lookup.on_response(&queries[0], lookup_response(None, closer.clone()));
+        lookup.on_failure(&queries[1]);
+
+        loop {
+            let queries = lookup.next_queries(now);
+            if lookup.is_finished() {
+                break;
+            }
+            assert!(!queries.is_empty(), "Lookup stalled without finishing");
+            for contact in queries {
+                lookup.on_response(&contact, lookup_response(None, Vec::new()));
+            }
+        }
+
+        let report = lookup.finish();
+        let ids: Vec<KademliaID> = report.result.iter().map(|c| c.id).collect();
+        let expected: Vec<KademliaID> = closer.iter().map(|c| c.id).collect();
+        assert_eq!(ids, expected, "Expected the k closest responsive contacts");
+        assert_eq!(report.rounds, 2, "Closer contacts are one hop away");
+        assert_eq!(report.queried.len(), 5);
+        assert_eq!(report.responsive.len(), 4);
+        assert_eq!(report.timed_out.len(), 1);
+        assert_eq!(report.timed_out[0].id, queries[1].id);
+        assert!(report.value_from.is_none());
+        assert_eq!(
+            report.closest_without_value.map(|c| c.id),
+            Some(closer[0].id)
+        );
+    }
+
+    #[test]
+    fn test_disjoint_paths_never_share_a_contact() {
+        let target_id = KademliaID::new();
+        let config = KademliaConfig {
+            k: 4,
+            alpha: 1,
+            disjoint_paths: 2,
+            ..test_config()
+        };
+        let start = vec![
+            contact_at_distance(target_id, 0x80),
+            contact_at_distance(target_id, 0x81),
+        ];
+        let mut lookup = Lookup::new(FindNode, KademliaID::new(), target_id, &config, start);
+        let now = tokio::time::Instant::now();
+
+        let queries = lookup.next_queries(now);
+        assert_eq!(queries.len(), 2, "One query per path");
+
+        // both peers return the same closest contact, only the first path gets it
+        let shared = contact_at_distance(target_id, 1);
+        lookup.on_response(
+            &queries[0],
+            lookup_response(
+                None,
+                vec![shared.clone(), contact_at_distance(target_id, 2)],
+            ),
+        );
+        lookup.on_response(
+            &queries[1],
+            lookup_response(None, vec![shared, contact_at_distance(target_id, 3)]),
+        );
+
+        loop {
+            let queries = lookup.next_queries(now);
+            if lookup.is_finished() {
+                break;
+            }
+            assert!(!queries.is_empty(), "Lookup stalled without finishing");
+            for contact in queries {
+                lookup.on_response(&contact, lookup_response(None, Vec::new()));
+            }
+        }
+
+        let report = lookup.finish();
+        let mut queried: Vec<KademliaID> = report.queried.iter().map(|c| c.id).collect();
+        queried.sort();
+        queried.dedup();
+        assert_eq!(queried.len(), 5, "Every contact is queried exactly once");
+        assert_eq!(report.queried.len(), 5);
+        let ids: Vec<KademliaID> = report.result.iter().map(|c| c.id).collect();
+        let expected: Vec<KademliaID> = [1, 2, 3, 0x80]
+            .iter()
+            .map(|i| contact_at_distance(target_id, *i).id)
+            .collect();
+        assert_eq!(ids, expected, "Paths are merged into the k closest");
+    }
+
+    #[test]
+    fn test_lookup_stops_when_value_is_found() {
+        let target_id = KademliaID::from_data("value");
+        let contacts: Vec<Contact> = (1..5).map(|i| contact_at_distance(target_id, i)).collect();
+        let mut lookup = Lookup::new(
+            FindValue::new(target_id),
+            KademliaID::new(),
+            target_id,
+            &test_config(),
+            contacts,
+        );
+        let now = tokio::time::Instant::now();
+
+        let queries = lookup.next_queries(now);
+        lookup.on_response(
+            &queries[1],
+            lookup_response(Some("value".to_string()), Vec::new()),
+        );
+
+        assert!(lookup.is_finished());
+        assert!(lookup.next_queries(now).is_empty());
+        let report = lookup.finish();
+        assert_eq!(report.result, Some("value".to_string()));
+        assert_eq!(report.value_from.map(|c| c.id), Some(queries[1].id));
+        assert!(
+            report.closest_without_value.is_none(),
+            "No other peer has answered yet"
+        );
+    }
+
+    #[test]
+    fn test_lookup_replaces_stalled_queries() {
+        let target_id = KademliaID::new();
+        let config = KademliaConfig {
+            alpha: 1,
+            stall_timeout: Duration::from_millis(100),
+            ..test_config()
+        };
+        let contacts: Vec<Contact> = (1..4).map(|i| contact_at_distance(target_id, i)).collect();
+        let mut lookup = Lookup::new(FindNode, KademliaID::new(), target_id, &config, contacts);
+        let now = tokio::time::Instant::now();
+
+        let first = lookup.next_queries(now);
+        assert_eq!(first.len(), 1);
+        assert_eq!(
+            lookup.next_stall_deadline(),
+            Some(now + config.stall_timeout)
+        );
+        assert!(lookup.next_queries(now).is_empty());
+
+        let second = lookup.next_queries(now + config.stall_timeout);
+        assert_eq!(second.len(), 1, "A stalled query should free its slot");
+        assert_ne!(first[0].id, second[0].id);
+
+        // a late answer from the stalled peer still counts
+        lookup.on_response(&first[0], lookup_response(None, Vec::new()));
+        assert!(!lookup.is_finished());
+    }
+
+    #[tokio::test]
+    async fn test_iterative_store_reports_replicas() {
+        let target_id = KademliaID::from_data("replicated");
+        let replica = spawn_local_node(Vec::new(), 20).await;
+        let node = spawn_local_node(Vec::new(), 20).await;
+
+        // a peer that never acknowledges
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for contact in [
+            replica.own_contact(),
+            Contact::new(KademliaID::new(), silent.local_addr().unwrap()),
+        ] {
+            node.route_table_tx
+                .send(RouteTableCMD::AddContact(contact))
+                .await
+                .unwrap();
+        }
+
+        let report = node
+            .iterative_store(target_id, "replicated".to_string())
+            .await
+            .unwrap();
+        let _ = tokio::fs::remove_file(
+            replica
+                .config
+                .storage_dir
+                .join(format!("{}.txt", target_id.to_hex())),
+        )
+        .await;
+
+        assert_eq!(report.key, target_id);
+        assert!(report.reached_quorum());
+        assert_eq!(report.stored.len(), 1);
+        assert_eq!(report.stored[0].id, replica.own_id);
+    }
+
+    #[tokio::test]
+    async fn test_only_publisher_can_delete_value() {
+        let dir = std::env::temp_dir().join(format!("kadrustlia-{}", KademliaID::new().to_hex()));
+        let key = KademliaID::from_data("value");
+        let publisher = KademliaID::new();
+        let other = KademliaID::new();
+
+        storage::store_value(&dir, &key, "value", Some(publisher))
+            .await
+            .unwrap();
+        // a later store by someone else does not take over the key
+        storage::store_value(&dir, &key, "value", Some(other))
+            .await
+            .unwrap();
+        assert_eq!(
+            storage::load_publisher(&dir, &key).await.unwrap(),
+            Some(publisher)
+        );
+
+        let refused = storage::delete_value(&dir, &key, &other).await;
+        assert_eq!(
+            refused.map_err(|e| e.kind()),
+            Err(std::io::ErrorKind::PermissionDenied)
+        );
+        assert!(storage::delete_value(&dir, &key, &publisher).await.unwrap());
+        assert!(!storage::value_path(&dir, &key).exists());
+        assert!(!storage::delete_value(&dir, &key, &publisher).await.unwrap());
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_store_refuses_value_not_matching_key() {
+        let dir = std::env::temp_dir().join(format!("kadrustlia-{}", KademliaID::new().to_hex()));
+        let key = KademliaID::from_data("honest");
+
+        storage::store_value(&dir, &key, "honest", None)
+            .await
+            .unwrap();
+        let refused = storage::store_value(&dir, &key, "garbage", None).await;
+        assert_eq!(
+            refused.map_err(|e| e.kind()),
+            Err(std::io::ErrorKind::InvalidData)
+        );
+        assert_eq!(
+            tokio::fs::read_to_string(storage::value_path(&dir, &key))
+                .await
+                .unwrap(),
+            "honest",
+            "A good replica must survive a STORE of garbage"
+        );
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_iterative_delete() {
+        let target_id = KademliaID::from_data("mistake");
+        let replica = spawn_local_node(Vec::new(), 20).await;
+        let publisher = spawn_local_node(Vec::new(), 20).await;
+        let other = spawn_local_node(Vec::new(), 20).await;
+        for node in [&publisher, &other] {
+            node.route_table_tx
+                .send(RouteTableCMD::AddContact(replica.own_contact()))
+                .await
+                .unwrap();
+        }
+
+        publisher
+            .iterative_store(target_id, "mistake".to_string())
+            .await
+            .unwrap();
+        let value_file = storage::value_path(&replica.config.storage_dir, &target_id);
+        assert!(value_file.exists());
+
+        let refused = other.iterative_delete(target_id).await;
+        assert_eq!(
+            refused.map_err(|e| e.kind()).err(),
+            Some(std::io::ErrorKind::PermissionDenied)
+        );
+        assert!(value_file.exists(), "Only the publisher may delete");
+
+        let report = publisher.iterative_delete(target_id).await.unwrap();
+        assert_eq!(report.deleted.len(), 1);
+        assert!(!value_file.exists());
+    }
+    #[test]
+    fn test_mutable_record_signature() {
+        let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let record = MutableRecord::new(&signing_key, 7, "10.0.0.1:80".to_string());
+
+        assert!(record.verify());
+        assert_eq!(
+            record.key(),
+            MutableRecord::key_for(&signing_key.verifying_key())
+        );
+        assert_eq!(
+            MutableRecord::decode(&record.encode()),
+            Some(record.clone())
+        );
+        assert_eq!(MutableRecord::decode("plain value"), None);
+
+        let mut tampered = record.clone();
+        tampered.sequence = 8;
+        assert!(
+            !tampered.verify(),
+            "Changing the sequence breaks the signature"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_storage_keeps_newest_record() {
+        let dir = std::env::temp_dir().join(format!("kadrustlia-{}", KademliaID::new().to_hex()));
+        let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let first = MutableRecord::new(&signing_key, 1, "first".to_string());
+        let second = MutableRecord::new(&signing_key, 2, "second".to_string());
+        let key = first.key();
+
+        storage::store_value(&dir, &key, &second.encode(), None)
+            .await
+            .unwrap();
+        assert!(
+            storage::store_value(&dir, &key, &first.encode(), None)
+                .await
+                .is_err(),
+            "An older sequence must not replace a newer one"
+        );
+        let stored = tokio::fs::read_to_string(storage::value_path(&dir, &key))
+            .await
+            .unwrap();
+        assert_eq!(MutableRecord::decode(&stored), Some(second.clone()));
+
+        // signed by someone else than the key it is stored under
+        let forged = MutableRecord::new(
+            &SigningKey::generate(&mut rand::rngs::OsRng),
+            3,
+            "forged".to_string(),
+        );
+        assert!(storage::store_value(&dir, &key, &forged.encode(), None)
+            .await
+            .is_err());
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_record_stores_keep_newest() {
+        let dir = std::env::temp_dir().join(format!("kadrustlia-{}", KademliaID::new().to_hex()));
+        let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let key = MutableRecord::key_for(&signing_key.verifying_key());
+
+        let stores: Vec<_> = (1..=32u64)
+            .rev()
+            .map(|sequence| {
+                let dir = dir.clone();
+                let data =
+                    MutableRecord::new(&signing_key, sequence, sequence.to_string()).encode();
+                tokio::spawn(async move { storage::store_value(&dir, &key, &data, None).await })
+            })
+            .collect();
+        for store in stores {
+            let _ = store.await.unwrap();
+        }
+
+        let stored = tokio::fs::read_to_string(storage::value_path(&dir, &key))
+            .await
+            .unwrap();
+        assert_eq!(
+            MutableRecord::decode(&stored).map(|record| record.sequence),
+            Some(32),
+            "An older sequence must never overwrite a newer one"
+        );
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_only_record_key_can_delete_record() {
+        let dir = std::env::temp_dir().join(format!("kadrustlia-{}", KademliaID::new().to_hex()));
+        let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let record = MutableRecord::new(&signing_key, 1, "owned".to_string());
+        let key = record.key();
+        let first_storer = KademliaID::new();
+
+        storage::store_value(&dir, &key, &record.encode(), Some(first_storer))
+            .await
+            .unwrap();
+        let refused = storage::delete_value(&dir, &key, &first_storer).await;
+        assert_eq!(
+            refused.map_err(|e| e.kind()),
+            Err(std::io::ErrorKind::PermissionDenied),
+            "Storing a record first does not make it yours"
+        );
+        assert!(storage::delete_value(&dir, &key, &key).await.unwrap());
+        assert!(!storage::value_path(&dir, &key).exists());
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+
+    #[test]
+    fn test_find_value_returns_newest_record() {
+        let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let target_id = MutableRecord::key_for(&signing_key.verifying_key());
+        let contacts: Vec<Contact> = (1..4).map(|i| contact_at_distance(target_id, i)).collect();
+        let mut lookup = Lookup::new(
+            FindValue::new(target_id),
+            KademliaID::new(),
+            target_id,
+            &test_config(),
+            contacts,
+        );
+        let now = tokio::time::Instant::now();
+
+        let queries = lookup.next_queries(now);
+        assert_eq!(queries.len(), 3);
+        for (contact, sequence) in queries.iter().zip([2, 5, 3]) {
+            let record = MutableRecord::new(&signing_key, sequence, format!("v{}", sequence));
+            lookup.on_response(contact, lookup_response(Some(record.encode()), Vec::new()));
+        }
+
+        lookup.next_queries(now);
+        assert!(lookup.is_finished());
+        let report = lookup.finish();
+        assert_eq!(report.result, Some("v5".to_string()));
+        assert_eq!(report.value_from.map(|c| c.id), Some(queries[1].id));
+        assert_eq!(
+            report.closest_without_value.map(|c| c.id),
+            Some(queries[0].id),
+            "The closest peer only had an older record"
+        );
+    }
+
+    #[test]
+    fn test_find_value_skips_peers_with_the_newest_record() {
+        let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let target_id = MutableRecord::key_for(&signing_key.verifying_key());
+        let contacts: Vec<Contact> = (1..4).map(|i| contact_at_distance(target_id, i)).collect();
+        let mut lookup = Lookup::new(
+            FindValue::new(target_id),
+            KademliaID::new(),
+            target_id,
+            &test_config(),
+            contacts,
+        );
+        let now = tokio::time::Instant::now();
+
+        let queries = lookup.next_queries(now);
+        let record = MutableRecord::new(&signing_key, 4, "v4".to_string()).encode();
+        lookup.on_response(
+            &queries[0],
+            lookup_response(Some(record.clone()), Vec::new()),
+        );
+        lookup.on_response(&queries[1], lookup_response(Some(record), Vec::new()));
+        lookup.on_response(&queries[2], lookup_response(None, Vec::new()));
+
+        let report = lookup.finish();
+        assert_eq!(report.value_from.map(|c| c.id), Some(queries[0].id));
+        assert_eq!(
+            report.closest_without_value.map(|c| c.id),
+            Some(queries[2].id)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_put_record_replaces_older_sequence() {
+        let replica = spawn_local_node(Vec::new(), 20).await;
+        let node = spawn_local_node(Vec::new(), 20).await;
+        node.route_table_tx
+            .send(RouteTableCMD::AddContact(replica.own_contact()))
+            .await
+            .unwrap();
+
+        let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let key = MutableRecord::key_for(&signing_key.verifying_key());
+        node.put_record(&signing_key, 1, "old".to_string())
+            .await
+            .unwrap();
+        node.put_record(&signing_key, 2, "new".to_string())
+            .await
+            .unwrap();
+        assert!(
+            node.put_record(&signing_key, 1, "old".to_string())
+                .await
+                .is_err(),
+            "Replicas should refuse an older sequence"
+        );
+
+        let report = node.iterative_find_value(key).await.unwrap();
+        let _ =
+            tokio::fs::remove_file(storage::value_path(&replica.config.storage_dir, &key)).await;
+        assert_eq!(report.result, Some("new".to_string()));
+    }
+    #[test]
+    fn test_find_value_rejects_mismatched_data() {
+        let target_id = KademliaID::from_data("genuine");
+        let contacts: Vec<Contact> = (1..3).map(|i| contact_at_distance(target_id, i)).collect();
+        let mut lookup = Lookup::new(
+            FindValue::new(target_id),
+            KademliaID::new(),
+            target_id,
+            &test_config(),
+            contacts,
+        );
+        let now = tokio::time::Instant::now();
+
+        let queries = lookup.next_queries(now);
+        lookup.on_response(
+            &queries[0],
+            lookup_response(Some("forged".to_string()), Vec::new()),
+        );
+        assert!(
+            !lookup.is_finished(),
+            "A bad value should not end the lookup"
+        );
+        lookup.on_response(
+            &queries[1],
+            lookup_response(Some("genuine".to_string()), Vec::new()),
+        );
+
+        let report = lookup.finish();
+        assert_eq!(report.result, Some("genuine".to_string()));
+        assert_eq!(report.value_from.map(|c| c.id), Some(queries[1].id));
+        assert_eq!(report.rejected.len(), 1);
+        assert_eq!(report.rejected[0].id, queries[0].id);
+    }
+
+    #[test]
+    fn test_remove_contact_from_routing_table() {
+        let my_id = KademliaID::new();
+        let mut routing_table =
+            RoutingTable::new(Contact::new(my_id, "127.0.0.1:5678".parse().unwrap()));
+        let contact = Contact::new(
+            my_id.generate_random_id_in_bucket(3),
+            "127.0.0.3:5678".parse().unwrap(),
+        );
+
+        routing_table.add_contact(contact.clone());
+        routing_table.remove_contact(contact.id);
+        assert!(routing_table.contacts().is_empty());
+    }
+    #[test]
+    fn test_signed_rpc_rejects_tampering() {
+        let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let message = RpcMessage::Error {
+            rpc_id: KademliaID::new(),
+            message: "hello".to_string(),
+        };
+        let recipient = KademliaID::new();
+        let envelope = Envelope {
+            nonce: 7,
+            tcp: false,
+            client: true,
+            recipient: Some(recipient),
+            timestamp: 1_000,
+        };
+        let bytes = bincode::serialize(&SignedRpc::seal(&signing_key, envelope, &message)).unwrap();
+
+        let signed: SignedRpc = bincode::deserialize(&bytes).unwrap();
+        let (sender, _) = signed.open().expect("Valid signature should open");
+        assert_eq!(
+            sender.id,
+            KademliaID::from_public_key(&signing_key.verifying_key())
+        );
+        assert_eq!(sender.nonce, 7);
+        assert!(!sender.tcp && sender.client);
+        assert_eq!(sender.recipient, Some(recipient));
+        assert_eq!(sender.timestamp, 1_000);
+
+        // flip a byte of the payload, right after the key, the nonce, the
+        // transport and client flags, the recipient, the timestamp and the
+        // length prefix
+        let mut tampered = bytes.clone();
+        tampered[32 + 8 + 1 + 1 + 21 + 8 + 8 + 4] ^= 0xff;
+        let signed: SignedRpc = bincode::deserialize(&tampered).unwrap();
+        assert!(signed.open().is_none(), "Tampered payload must not verify");
+
+        // the nonce is covered by the signature as well
+        let mut tampered = bytes.clone();
+        tampered[32] ^= 0xff;
+        let signed: SignedRpc = bincode::deserialize(&tampered).unwrap();
+        assert!(signed.open().is_none(), "Tampered nonce must not verify");
+
+        let mut tampered = bytes.clone();
+        tampered[32 + 8] ^= 1;
+        let signed: SignedRpc = bincode::deserialize(&tampered).unwrap();
+        assert!(
+            signed.open().is_none(),
+            "Tampered transports must not verify"
+        );
+
+        let mut tampered = bytes.clone();
+        tampered[32 + 8 + 1] ^= 1;
+        let signed: SignedRpc = bincode::deserialize(&tampered).unwrap();
+        assert!(signed.open().is_none(), "A client must not pass as a node");
+
+        let mut tampered = bytes.clone();
+        tampered[32 + 8 + 1 + 1 + 1] ^= 0xff;
+        let signed: SignedRpc = bincode::deserialize(&tampered).unwrap();
+        assert!(
+            signed.open().is_none(),
+            "A message must not be readdressed to another node"
+        );
+
+        let mut tampered = bytes.clone();
+        tampered[32 + 8 + 1 + 1 + 21] ^= 0xff;
+        let signed: SignedRpc = bincode::deserialize(&tampered).unwrap();
+        assert!(
+            signed.open().is_none(),
+            "Tampered send time must not verify"
+        );
+    }
+
+    #[test]
+    fn test_seen_requests_reject_replays_and_stale_messages() {
+        let mut seen = SeenRequests::new(60, 2);
+        let sender = KademliaID::new();
+        let rpc_id = KademliaID::new();
+
+        assert!(seen.first_sighting(sender, rpc_id, 1_000, 1_010));
+        assert!(!seen.first_sighting(sender, rpc_id, 1_000, 1_020), "Replay");
+        assert!(seen.first_sighting(KademliaID::new(), rpc_id, 1_000, 1_020));
+        assert!(
+            !seen.first_sighting(sender, KademliaID::new(), 900, 1_020),
+            "Sent too long ago"
+        );
+
+        seen.forget(sender, rpc_id);
+        assert!(seen.first_sighting(sender, rpc_id, 1_000, 1_020));
+    }
+
+    #[tokio::test]
+    async fn test_replayed_store_is_dropped() {
+        let dir = std::env::temp_dir().join(format!("kad-replay-{}", KademliaID::new().to_hex()));
+        let node = spawn_configured_node(KademliaConfig {
+            advertise_address: free_local_addr(),
+            storage_dir: dir.clone(),
+            ..test_config()
+        })
+        .await;
+        let other = spawn_local_node(Vec::new(), 20).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let value = "stored once".to_string();
+        let captured = |recipient| {
+            let request = RpcMessage::Request {
+                rpc_id: KademliaID::new(),
+                method: otherCommand::STORE,
+                target_id: Some(KademliaID::from_data(&value)),
+                data: Some(value.clone()),
+                contact: None,
+            };
+            let envelope = Envelope {
+                nonce: 0,
+                tcp: false,
+                client: false,
+                recipient: Some(recipient),
+                timestamp: utils::unix_time(),
+            };
+            let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+            bincode::serialize(&Packet::Plain(SignedRpc::seal(
+                &signing_key,
+                envelope,
+                &request,
+            )))
+            .unwrap()
+        };
+        let answered = |bytes: Vec<u8>, to: SocketAddr| {
+            let socket = &socket;
+            async move {
+                socket.send_to(&bytes, to).await.unwrap();
+                let mut buf = [0u8; 65507];
+                tokio::time::timeout(Duration::from_millis(300), socket.recv_from(&mut buf))
+                    .await
+                    .is_ok()
+            }
+        };
+
+        let store = captured(node.own_id);
+        assert!(answered(store.clone(), node.config.advertise_address).await);
+        assert!(
+            !answered(store.clone(), node.config.advertise_address).await,
+            "A replayed STORE must be dropped"
+        );
+        assert!(
+            !answered(store, other.config.advertise_address).await,
+            "A STORE addressed to another node must be dropped"
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_puzzle_solutions_verify() {
+        let signing_key = puzzle::generate_key(8);
+        let kad_id = KademliaID::from_public_key(&signing_key.verifying_key());
+        assert!(puzzle::solves_static(&kad_id, 8));
+
+        let nonce = puzzle::solve_dynamic(&kad_id, 8);
+        assert!(puzzle::verify_contact(
+            &Contact::with_nonce(kad_id, "127.0.0.1:5678".parse().unwrap(), nonce),
+            8,
+            8
+        ));
+        // difficulty 0 accepts anything
+        assert!(puzzle::verify_contact(
+            &Contact::new(KademliaID::new(), "127.0.0.1:5678".parse().unwrap()),
+            0,
+            0
+        ));
+    }
+
+    #[test]
+    fn test_routing_table_rejects_unsolved_puzzle() {
+        let config = KademliaConfig {
+            static_puzzle_difficulty: 8,
+            dynamic_puzzle_difficulty: 8,
+            ..test_config()
+        };
+        let me = Contact::new(KademliaID::new(), "127.0.0.1:5678".parse().unwrap());
+        let mut routing_table = RoutingTable::with_config(me, &config);
+
+        // one in 2^16 random ids would pass both puzzles by accident, make sure this one does not
+        let mut unsolved = KademliaID::new();
+        while puzzle::verify_contact(
+            &Contact::new(unsolved, "127.0.0.1:5678".parse().unwrap()),
+            8,
+            8,
+        ) {
+            unsolved = KademliaID::new();
+        }
+        routing_table.add_contact(Contact::new(unsolved, "127.0.0.1:5679".parse().unwrap()));
+        assert!(routing_table.contacts().is_empty());
+
+        let kad_id = KademliaID::from_public_key(&puzzle::generate_key(8).verifying_key());
+        let nonce = puzzle::solve_dynamic(&kad_id, 8);
+        routing_table.add_contact(Contact::with_nonce(
+            kad_id,
+            "127.0.0.1:5680".parse().unwrap(),
+            nonce,
+        ));
+        assert_eq!(routing_table.contacts().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_ping_is_not_added() {
+        let node = spawn_local_node(Vec::new(), 20).await;
+
+        // signed by one key but claiming someone else's id
+        let spoofer = Networking::new();
+        let victim_id = KademliaID::new();
+        spoofer
+            .send_rpc_request(
+                KademliaID::new(),
+                &node.config.advertise_address,
+                otherCommand::PING,
+                None,
+                None,
+                Some(vec![Contact::new(
+                    victim_id,
+                    "127.0.0.1:1".parse().unwrap(),
+                )]),
+            )
+            .await
+            .unwrap();
+
+        // unsigned garbage should be dropped without taking the node down
+        let raw = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        raw.send_to(b"not a signed rpc", &node.config.advertise_address)
+            .await
+            .unwrap();
+
+        let honest = spawn_local_node(Vec::new(), 20).await;
+        let response = honest
+            .networking
+            .send_rpc_request_await(
+                KademliaID::new(),
+                &node.config.advertise_address,
+                otherCommand::PING,
+                None,
+                None,
+                Some(vec![honest.own_contact()]),
+            )
+            .await
+            .unwrap();
+        assert!(response.is_some(), "Node should still answer PINGs");
+
+        let (reply_tx, mut reply_rx) = mpsc::channel(1);
+        node.route_table_tx
+            .send(RouteTableCMD::GetAllContacts(reply_tx))
+            .await
+            .unwrap();
+        let ids: Vec<KademliaID> = reply_rx
+            .recv()
+            .await
+            .unwrap()
+            .iter()
+            .map(|c| c.id)
+            .collect();
+        assert!(!ids.contains(&victim_id), "Spoofed id must not be added");
+        assert!(ids.contains(&honest.own_id));
+    }
+
+    #[test]
+    fn test_rate_limiter_refills() {
+        let mut limiter = RateLimiter::new(10, 2);
+        let addr: std::net::IpAddr = "10.0.0.1".parse().unwrap();
+        let now = std::time::Instant::now();
+
+        assert!(limiter.allow(addr, now));
+        assert!(limiter.allow(addr, now));
+        assert!(!limiter.allow(addr, now), "Burst is used up");
+        assert!(
+            limiter.allow("10.0.0.2".parse().unwrap(), now),
+            "Other addresses have their own bucket"
+        );
+        assert!(limiter.allow(addr, now + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_store_quota_counts_distinct_keys() {
+        let mut quota = StoreQuota::new(1, 10);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let peer = KademliaID::new();
+        let (first, second) = (KademliaID::new(), KademliaID::new());
+
+        assert!(quota.allows(&peer, ip, &first));
+        quota.add(peer, ip, first);
+        assert!(
+            quota.allows(&peer, ip, &first),
+            "Storing a key again is fine"
+        );
+        assert!(!quota.allows(&peer, ip, &second));
+        assert!(quota.allows(&KademliaID::new(), ip, &second));
+
+        quota.remove(&peer, ip, &first);
+        assert!(quota.allows(&peer, ip, &second));
+    }
+
+    #[test]
+    fn test_store_quota_limits_fresh_ids_from_one_subnet() {
+        let mut quota = StoreQuota::new(1, 3);
+        let neighbour = |host: u8| IpAddr::from([10, 0, 0, host]);
+
+        // every store comes from a new id, only the subnet can tell them apart
+        for host in 1..=3 {
+            let key = KademliaID::new();
+            assert!(quota.allows(&KademliaID::new(), neighbour(host), &key));
+            quota.add(KademliaID::new(), neighbour(host), key);
+        }
+        assert!(
+            !quota.allows(&KademliaID::new(), neighbour(4), &KademliaID::new()),
+            "The /24 has used up its quota"
+        );
+        assert!(quota.allows(
+            &KademliaID::new(),
+            IpAddr::from([10, 0, 1, 1]),
+            &KademliaID::new()
+        ));
+
+        // tracking is bounded however many ids show up
+        for _ in 0..20_000 {
+            quota.add(KademliaID::new(), neighbour(9), KademliaID::new());
+        }
+        assert!(quota.tracked_storers() <= 10_000);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_skips_peers_that_refuse_us() {
+        let dir = std::env::temp_dir().join(format!("kad-refusing-{}", KademliaID::new().to_hex()));
+        let limited = spawn_configured_node(KademliaConfig {
+            advertise_address: free_local_addr(),
+            rate_limit: 0,
+            rate_burst: 0,
+            storage_dir: dir.clone(),
+            ..test_config()
+        })
+        .await;
+        let honest = spawn_local_node(Vec::new(), 20).await;
+        let node = spawn_local_node(Vec::new(), 20).await;
+        for peer in [&limited, &*honest] {
+            node.route_table_tx
+                .send(RouteTableCMD::AddContact(peer.own_contact()))
+                .await
+                .unwrap();
+        }
+
+        let report = node.iterative_find_node(KademliaID::new()).await.unwrap();
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        let ids: Vec<KademliaID> = report.result.iter().map(|c| c.id).collect();
+        assert!(ids.contains(&honest.own_id));
+        assert!(
+            !ids.contains(&limited.own_id),
+            "A peer that rate limits us is no lookup result"
+        );
+        assert!(report.responsive.iter().all(|c| c.id != limited.own_id));
+        assert!(report.timed_out.iter().any(|c| c.id == limited.own_id));
+    }
+
+    #[tokio::test]
+    async fn test_flooding_and_oversized_stores_get_errors() {
+        let dir = std::env::temp_dir().join(format!("kad-limits-{}", KademliaID::new().to_hex()));
+        let node = spawn_configured_node(KademliaConfig {
+            advertise_address: free_local_addr(),
+            rate_limit: 1,
+            rate_burst: 3,
+            max_store_size: 8,
+            storage_dir: dir.clone(),
+            ..test_config()
+        })
+        .await;
+        let sender = spawn_local_node(Vec::new(), 20).await;
+        let client = sender.networking.clone();
+
+        let value = "too large for this node".to_string();
+        let response = client
+            .send_rpc_request_to_contact(
+                KademliaID::new(),
+                &node.own_contact(),
+                otherCommand::STORE,
+                Some(KademliaID::from_data(&value)),
+                Some(value),
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(response, Some(RpcMessage::Error { .. })));
+
+        let mut replies = Vec::new();
+        for _ in 0..3 {
+            replies.push(
+                client
+                    .send_rpc_request_await(
+                        KademliaID::new(),
+                        &node.config.advertise_address,
+                        otherCommand::PING,
+                        None,
+                        None,
+                        None,
+                    )
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert!(matches!(replies[0], Some(RpcMessage::Response { .. })));
+        assert!(matches!(replies[1], Some(RpcMessage::Response { .. })));
+        assert!(
+            matches!(replies[2], Some(RpcMessage::Error { .. })),
+            "The request over the burst should be refused"
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_bucket_limits_contacts_per_ip_and_subnet() {
+        let mut bucket = Bucket::with_limits(
+            20,
+            DiversityLimits {
+                per_ip: 1,
+                per_subnet: 2,
+            },
+        );
+        for address in [
+            "10.0.0.1:5678",
+            "10.0.0.1:5679",
+            "10.0.0.2:5678",
+            "10.0.0.3:5678",
+            "10.0.1.1:5678",
+        ] {
+            let contact = Contact::new(KademliaID::new(), address.parse().unwrap());
+            bucket.add_contact(&contact, contact.id);
+        }
+        let mut addresses: Vec<String> = bucket
+            .contacts()
+            .into_iter()
+            .map(|c| c.address.to_string())
+            .collect();
+        addresses.sort();
+        assert_eq!(
+            addresses,
+            vec!["10.0.0.1:5678", "10.0.0.2:5678", "10.0.1.1:5678"],
+            "A second contact from one IP and a third from one /24 are refused"
+        );
+    }
+
+    #[test]
+    fn test_routing_table_limits_contacts_per_ip() {
+        let config = KademliaConfig {
+            max_contacts_per_ip: 2,
+            ..test_config()
+        };
+        let me = Contact::new(KademliaID::new(), "10.0.0.1:5678".parse().unwrap());
+        let mut routing_table = RoutingTable::with_config(me, &config);
+
+        for port in 0..5 {
+            routing_table.add_contact(Contact::new(
+                KademliaID::new(),
+                SocketAddr::from(([10, 0, 0, 2], 6000 + port)),
+            ));
+        }
+        routing_table.add_contact(Contact::new(
+            KademliaID::new(),
+            "10.0.0.3:6000".parse().unwrap(),
+        ));
+
+        let contacts = routing_table.contacts();
+        assert_eq!(contacts.len(), 3);
+        assert_eq!(
+            contacts
+                .iter()
+                .filter(|c| c.address.ip().to_string() == "10.0.0.2")
+                .count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_responses_must_match_a_pending_request() {
+        let node = spawn_local_node(Vec::new(), 20).await;
+        let node_addr = node.config.advertise_address.to_string();
+
+        // nobody asked for this one, the contacts in it must not end up anywhere
+        let sprayer = Networking::new();
+        sprayer
+            .send_rpc_response(
+                KademliaID::new(),
+                &node_addr,
+                otherCommand::FINDNODE,
+                None,
+                Some(vec![Contact::new(
+                    KademliaID::new(),
+                    "127.0.0.1:1".parse().unwrap(),
+                )]),
+            )
+            .await
+            .unwrap();
+
+        let peer = Networking::new();
+        let peer_addr = peer.bind(free_local_addr()).await.unwrap();
+        let rpc_id = KademliaID::new();
+        let requester = node.networking.clone();
+        let request = tokio::spawn(async move {
+            requester
+                .send_rpc_request_await(rpc_id, peer_addr, otherCommand::PING, None, None, None)
+                .await
+        });
+        sleep(Duration::from_millis(100)).await;
+
+        // the wrong command from the right peer and the right command from the wrong one
+        peer.send_rpc_response(rpc_id, &node_addr, otherCommand::FINDNODE, None, None)
+            .await
+            .unwrap();
+        sprayer
+            .send_rpc_response(rpc_id, &node_addr, otherCommand::PONG, None, None)
+            .await
+            .unwrap();
+        peer.send_rpc_response(rpc_id, &node_addr, otherCommand::PONG, None, None)
+            .await
+            .unwrap();
+
+        let response = request.await.unwrap().unwrap();
+        assert!(matches!(
+            response,
+            Some(RpcMessage::Response {
+                result: otherCommand::PONG,
+                ..
+            })
+        ));
+        assert_eq!(node.networking.unmatched_responses(), 3);
+
+        let (reply_tx, mut reply_rx) = mpsc::channel(1);
+        node.route_table_tx
+            .send(RouteTableCMD::GetAllContacts(reply_tx))
+            .await
+            .unwrap();
+        let contacts = reply_rx.recv().await.unwrap();
+        assert_eq!(
+            contacts.len(),
+            1,
+            "Only the peer that really answered is added"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup_does_not_add_unverified_contacts() {
+        let node = spawn_configured_node(KademliaConfig {
+            advertise_address: free_local_addr(),
+            rpc_timeout: Duration::from_millis(300),
+            stall_timeout: Duration::from_millis(100),
+            ..test_config()
+        })
+        .await;
+        let peer = spawn_local_node(vec![node.config.advertise_address.to_string()], 20).await;
+        peer.join().await.unwrap();
+
+        // the peer hands out a contact that never answers
+        let ghost = Contact::new(KademliaID::new(), free_local_addr());
+        peer.route_table_tx
+            .send(RouteTableCMD::AddContact(ghost.clone()))
+            .await
+            .unwrap();
+
+        let report = node.iterative_find_node(ghost.id).await.unwrap();
+        assert!(report.queried.iter().any(|c| c.id == ghost.id));
+
+        let (reply_tx, mut reply_rx) = mpsc::channel(1);
+        node.route_table_tx
+            .send(RouteTableCMD::GetAllContacts(reply_tx))
+            .await
+            .unwrap();
+        let ids: Vec<KademliaID> = reply_rx
+            .recv()
+            .await
+            .unwrap()
+            .iter()
+            .map(|c| c.id)
+            .collect();
+        assert!(ids.contains(&peer.own_id));
+        assert!(
+            !ids.contains(&ghost.id),
+            "Contacts we only heard about stay out of the routing table"
+        );
+    }
+
+    #[test]
+    fn test_session_handshake_and_sealing() {
+        let (alice_key, bob_key) = (
+            SigningKey::generate(&mut rand::rngs::OsRng),
+            SigningKey::generate(&mut rand::rngs::OsRng),
+        );
+        let alice_addr: std::net::SocketAddr = "10.0.0.1:5678".parse().unwrap();
+        let bob_addr: std::net::SocketAddr = "10.0.0.2:5678".parse().unwrap();
+        let (mut alice, mut bob) = (Sessions::default(), Sessions::default());
+        let alice_id = KademliaID::from_public_key(&alice_key.verifying_key());
+        let bob_id = KademliaID::from_public_key(&bob_key.verifying_key());
+
+        let (hello, mut done) = alice.begin(&alice_key, bob_addr, Some(bob_id));
+        let reply = bob
+            .accept(&bob_key, &hello, alice_addr)
+            .expect("A new handshake is answered");
+        assert!(alice.accept(&alice_key, &reply, bob_addr).is_none());
+        assert!(done.try_recv().is_ok(), "Alice is told the session is up");
+        assert!(
+            alice.has_session(&bob_addr, Some(bob_id))
+                && bob.has_session(&alice_addr, Some(alice_id))
+        );
+
+        let Some(Packet::Sealed {
+            session_id,
+            nonce,
+            ciphertext,
+        }) = alice.seal(&bob_addr, Some(bob_id), b"hello bob")
+        else {
+            panic!("Expected a sealed packet");
+        };
+        assert!(!ciphertext.windows(5).any(|w| w == b"hello"));
+        let (plaintext, peer_id) = bob
+            .open(alice_addr, &session_id, &nonce, &ciphertext)
+            .unwrap();
+        assert_eq!(plaintext, b"hello bob");
+        assert_eq!(peer_id, alice_id);
+
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 0xff;
+        assert!(bob
+            .open(alice_addr, &session_id, &nonce, &tampered)
+            .is_none());
+        assert!(Sessions::default()
+            .open(alice_addr, &session_id, &nonce, &ciphertext)
+            .is_none());
+    }
+
+    #[test]
+    fn test_sessions_reject_replays() {
+        let (alice_key, bob_key) = (
+            SigningKey::generate(&mut rand::rngs::OsRng),
+            SigningKey::generate(&mut rand::rngs::OsRng),
+        );
+        let alice_addr: std::net::SocketAddr = "10.0.0.1:5678".parse().unwrap();
+        let bob_addr: std::net::SocketAddr = "10.0.0.2:5678".parse().unwrap();
+        let (mut alice, mut bob) = (Sessions::default(), Sessions::default());
+        let alice_id = KademliaID::from_public_key(&alice_key.verifying_key());
+        let bob_id = KademliaID::from_public_key(&bob_key.verifying_key());
+
+        // an answer to one handshake does not finish another
+        let (first, _) = alice.begin(&alice_key, bob_addr, Some(bob_id));
+        let (second, _) = alice.begin(&alice_key, bob_addr, Some(bob_id));
+        let mut forged = bob.accept(&bob_key, &first, alice_addr).unwrap();
+        forged.session_id = second.session_id;
+        assert!(alice.accept(&alice_key, &forged, bob_addr).is_none());
+        assert!(!alice.has_session(&bob_addr, Some(bob_id)));
+
+        let reply = bob.accept(&bob_key, &second, alice_addr).unwrap();
+        alice.accept(&alice_key, &reply, bob_addr);
+        assert!(alice.has_session(&bob_addr, Some(bob_id)));
+
+        let mut sealed = Vec::new();
+        for message in [b"one", b"two", b"six"] {
+            let Some(Packet::Sealed {
+                session_id,
+                nonce,
+                ciphertext,
+            }) = alice.seal(&bob_addr, Some(bob_id), message)
+            else {
+                panic!("Expected a sealed packet");
+            };
+            sealed.push((session_id, nonce, ciphertext));
+        }
+        assert_ne!(sealed[0].1, sealed[1].1, "Every message gets its own nonce");
+
+        // late messages are fine, the same one twice is not
+        let open = |bob: &mut Sessions, (session_id, nonce, ciphertext): &(_, _, Vec<u8>)| {
+            bob.open(alice_addr, session_id, nonce, ciphertext)
+        };
+        assert!(open(&mut bob, &sealed[2]).is_some());
+        assert!(open(&mut bob, &sealed[0]).is_some());
+        assert!(open(&mut bob, &sealed[0]).is_none());
+        assert!(open(&mut bob, &sealed[2]).is_none());
+        assert!(open(&mut bob, &sealed[1]).is_some());
+
+        // bob's own messages count separately and still get through
+        let Some(Packet::Sealed {
+            session_id,
+            nonce,
+            ciphertext,
+        }) = bob.seal(&alice_addr, Some(alice_id), b"back")
+        else {
+            panic!("Expected a sealed packet");
+        };
+        assert_ne!(nonce, sealed[0].1);
+        assert!(alice
+            .open(bob_addr, &session_id, &nonce, &ciphertext)
+            .is_some());
+    }
+
+    #[test]
+    fn test_sessions_stay_with_the_node_we_meant() {
+        let (alice_key, bob_key, mallory_key) = (
+            SigningKey::generate(&mut rand::rngs::OsRng),
+            SigningKey::generate(&mut rand::rngs::OsRng),
+            SigningKey::generate(&mut rand::rngs::OsRng),
+        );
+        let alice_addr: std::net::SocketAddr = "10.0.0.1:5678".parse().unwrap();
+        let bob_addr: std::net::SocketAddr = "10.0.0.2:5678".parse().unwrap();
+        let bob_id = KademliaID::from_public_key(&bob_key.verifying_key());
+        let (mut alice, mut bob, mut mallory) = (
+            Sessions::default(),
+            Sessions::default(),
+            Sessions::default(),
+        );
+
+        // mallory sees our handshake to bob and answers it from bob's address
+        let (hello, mut done) = alice.begin(&alice_key, bob_addr, Some(bob_id));
+        let forged = mallory.accept(&mallory_key, &hello, alice_addr).unwrap();
+        assert!(alice.accept(&alice_key, &forged, bob_addr).is_none());
+        assert!(done.try_recv().is_err());
+        assert!(!alice.has_session(&bob_addr, Some(bob_id)));
+        assert!(!alice.has_session(&bob_addr, None));
+
+        // the handshake is still open for the real bob
+        let reply = bob.accept(&bob_key, &hello, alice_addr).unwrap();
+        alice.accept(&alice_key, &reply, bob_addr);
+        assert!(done.try_recv().is_ok());
+
+        // a handshake mallory starts from bob's address does not take over
+        let (spoofed, _) = mallory.begin(&mallory_key, alice_addr, None);
+        assert!(alice.accept(&alice_key, &spoofed, bob_addr).is_some());
+        for recipient in [Some(bob_id), None] {
+            let Some(Packet::Sealed {
+                session_id,
+                nonce,
+                ciphertext,
+            }) = alice.seal(&bob_addr, recipient, b"for bob")
+            else {
+                panic!("Expected a sealed packet");
+            };
+            assert!(bob
+                .open(alice_addr, &session_id, &nonce, &ciphertext)
+                .is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_nodes_talk_and_refuse_plaintext() {
+        let dir = std::env::temp_dir().join(format!("kad-sealed-{}", KademliaID::new().to_hex()));
+        let encrypted_node = || KademliaConfig {
+            advertise_address: free_local_addr(),
+            encrypt: true,
+            storage_dir: dir.clone(),
+            ..test_config()
+        };
+        let alice = spawn_configured_node(encrypted_node()).await;
+        let bob = spawn_configured_node(encrypted_node()).await;
+
+        let value = "sealed value".to_string();
+        let key = KademliaID::from_data(&value);
+        let response = alice
+            .networking
+            .send_rpc_request_to_contact(
+                KademliaID::new(),
+                &bob.own_contact(),
+                otherCommand::STORE,
+                Some(key),
+                Some(value.clone()),
+                Some(vec![alice.own_contact()]),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(response, Some(RpcMessage::Response { .. })));
+
+        let response = alice
+            .networking
+            .send_rpc_request_await(
+                KademliaID::new(),
+                &bob.config.advertise_address,
+                otherCommand::FINDVALUE,
+                Some(key),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            response,
+            Some(RpcMessage::Response { data: Some(data), .. }) if data == value
+        ));
+
+        let plain = spawn_configured_node(KademliaConfig {
+            advertise_address: free_local_addr(),
+            ..test_config()
+        })
+        .await;
+        let response = plain
+            .networking
+            .send_rpc_request_await(
+                KademliaID::new(),
+                &bob.config.advertise_address,
+                otherCommand::PING,
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(response.is_none(), "Plaintext requests are dropped");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_large_values_go_over_streams() {
+        let dir = std::env::temp_dir().join(format!("kad-stream-{}", KademliaID::new().to_hex()));
+        let large_node = || KademliaConfig {
+            advertise_address: free_local_addr(),
+            max_store_size: 1024 * 1024,
+            storage_dir: dir.clone(),
+            ..test_config()
+        };
+        let alice = spawn_configured_node(large_node()).await;
+        let bob = spawn_configured_node(large_node()).await;
+        assert!(alice.networking.accepts_streams() && bob.networking.accepts_streams());
+
+        // neither the request nor the response fit in a datagram
+        let value = "v".repeat(200_000);
+        let key = KademliaID::from_data(&value);
+        let response = alice
+            .networking
+            .send_rpc_request_to_contact(
+                KademliaID::new(),
+                &bob.own_contact(),
+                otherCommand::STORE,
+                Some(key),
+                Some(value.clone()),
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(response, Some(RpcMessage::Response { .. })));
+
+        let response = alice
+            .networking
+            .send_rpc_request_await(
+                KademliaID::new(),
+                &bob.config.advertise_address,
+                otherCommand::FINDVALUE,
+                Some(key),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            response,
+            Some(RpcMessage::Response { data: Some(data), .. }) if data == value
+        ));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_prefer_tcp_without_udp() {
+        let node = spawn_configured_node(KademliaConfig {
+            advertise_address: free_local_addr(),
+            ..test_config()
+        })
+        .await;
+        let client = Networking::with_config(
+            &KademliaConfig {
+                prefer_tcp: true,
+                ..test_config()
+            },
+            SigningKey::generate(&mut rand::rngs::OsRng),
+        );
+
+        // the client has no udp socket of its own to receive an answer on
+        let response = client
+            .send_rpc_request_await(
+                KademliaID::new(),
+                &node.config.advertise_address,
+                otherCommand::PING,
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            response,
+            Some(RpcMessage::Response {
+                result: otherCommand::PONG,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_node_bridges_address_families() {
+        let port = free_udp_port();
+        let bridge = Arc::new(Kademlia::with_config(
+            SigningKey::generate(&mut rand::rngs::OsRng),
+            KademliaConfig {
+                advertise_address: SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, port)),
+                ..test_config()
+            },
+        ));
+        let all_interfaces = SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, port));
+        bridge.bind(all_interfaces).await.unwrap();
+        let bridge_listen = Arc::clone(&bridge);
+        tokio::spawn(async move { bridge_listen.listen(all_interfaces).await });
+
+        let ipv4_node = spawn_local_node(vec![format!("127.0.0.1:{}", port)], 20).await;
+        let ipv6_node = spawn_configured_node(KademliaConfig {
+            advertise_address: SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, free_udp_port())),
+            boot_nodes: vec![format!("[::1]:{}", port)],
+            ..test_config()
+        })
+        .await;
+        ipv4_node.join().await.unwrap();
+        ipv6_node.join().await.unwrap();
+
+        let (reply_tx, mut reply_rx) = mpsc::channel(1);
+        bridge
+            .route_table_tx
+            .send(RouteTableCMD::GetAllContacts(reply_tx))
+            .await
+            .unwrap();
+        let contacts = reply_rx.recv().await.unwrap();
+        for node in [&ipv4_node, &ipv6_node] {
+            assert!(
+                contacts
+                    .iter()
+                    .any(|c| c.id == node.own_id && c.address == node.config.advertise_address),
+                "{} should be known by its own address, not a v4-mapped one",
+                node.config.advertise_address
+            );
+        }
+    }
+
+    #[test]
+    fn test_address_discovery_needs_agreeing_peers() {
+        let configured: SocketAddr = "10.0.0.5:5678".parse().unwrap();
+        let external: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        let mut discovery = AddressDiscovery::new(configured, true);
+
+        // one peer repeating itself is still one vote
+        assert!(discovery
+            .observe("198.51.100.1".parse().unwrap(), external)
+            .is_none());
+        assert!(discovery
+            .observe("198.51.100.1".parse().unwrap(), external)
+            .is_none());
+        assert!(discovery
+            .observe(
+                "198.18.0.2".parse().unwrap(),
+                "192.0.2.9:1".parse().unwrap()
+            )
+            .is_none());
+        assert!(discovery
+            .observe("192.0.2.3".parse().unwrap(), external)
+            .is_none());
+        assert_eq!(discovery.advertised(), configured);
+
+        assert_eq!(
+            discovery.observe("198.18.1.4".parse().unwrap(), external),
+            Some(external)
+        );
+        assert_eq!(discovery.advertised(), external);
+
+        let mut configured_only = AddressDiscovery::new(configured, false);
+        for peer in ["198.51.100.1", "198.18.0.2", "192.0.2.3"] {
+            assert!(configured_only
+                .observe(peer.parse().unwrap(), external)
+                .is_none());
+        }
+        assert_eq!(configured_only.advertised(), configured);
+    }
+
+    #[test]
+    fn test_colluding_peers_cannot_move_our_address() {
+        let configured: SocketAddr = "10.0.0.5:5678".parse().unwrap();
+        let fake: SocketAddr = "192.0.2.66:6666".parse().unwrap();
+        let mut discovery = AddressDiscovery::new(configured, true);
+
+        // two peers on different networks agreeing are not enough
+        for peer in ["198.51.100.1", "203.0.113.1"] {
+            assert!(discovery.observe(peer.parse().unwrap(), fake).is_none());
+        }
+        // and hosts sharing a subnet with them add nothing
+        for host in 2..=200 {
+            let peer = IpAddr::from([198, 51, 100, host]);
+            assert!(discovery.observe(peer, fake).is_none());
+        }
+        for peer in ["203.0.113.2", "203.0.113.254"] {
+            assert!(discovery.observe(peer.parse().unwrap(), fake).is_none());
+        }
+        assert_eq!(discovery.advertised(), configured);
+    }
+
+    #[tokio::test]
+    async fn test_advertised_addresses_are_checked_before_adding() {
+        let node = spawn_configured_node(KademliaConfig {
+            advertise_address: free_local_addr(),
+            ..test_config()
+        })
+        .await;
+        let peer_key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let peer_id = KademliaID::from_public_key(&peer_key.verifying_key());
+        let peer = Networking::with_config(&test_config(), peer_key.clone());
+        let peer_addr = peer.bind(free_local_addr()).await.unwrap();
+        let (peer_tx, _peer_rx) = mpsc::channel(32);
+        let peer_listen = peer.clone();
+        tokio::spawn(async move { peer_listen.listen_for_rpc(peer_tx, peer_addr).await });
+
+        // a contact nobody answers at stays out, but the PONG still says where we came from
+        let response = peer
+            .send_rpc_request_await(
+                KademliaID::new(),
+                node.config.advertise_address,
+                otherCommand::PING,
+                None,
+                None,
+                Some(vec![Contact::new(peer_id, free_local_addr())]),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            response,
+            Some(RpcMessage::Response { data: Some(observed), .. }) if observed == peer_addr.to_string()
+        ));
+
+        // from another socket, advertising the address the peer really answers at
+        let elsewhere = Networking::with_config(&test_config(), peer_key);
+        elsewhere
+            .send_rpc_request_await(
+                KademliaID::new(),
+                node.config.advertise_address,
+                otherCommand::PING,
+                None,
+                None,
+                Some(vec![Contact::new(peer_id, peer_addr)]),
+            )
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(700)).await;
+
+        let (reply_tx, mut reply_rx) = mpsc::channel(1);
+        node.route_table_tx
+            .send(RouteTableCMD::GetAllContacts(reply_tx))
+            .await
+            .unwrap();
+        let contacts = reply_rx.recv().await.unwrap();
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(
+            (contacts[0].id, contacts[0].address),
+            (peer_id, peer_addr),
+            "Only the address that answered our own PING is added"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_preferring_tcp_never_offers_streams() {
+        let client = spawn_configured_node(KademliaConfig {
+            advertise_address: free_local_addr(),
+            client: true,
+            prefer_tcp: true,
+            ..test_config()
+        })
+        .await;
+        assert!(!client.networking.accepts_streams());
+
+        // the request goes over a stream, it still says there is no
+        // listener behind it to answer
+        let peer = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let networking = client.networking.clone();
+        tokio::spawn(async move {
+            let _ = networking
+                .send_rpc_request_await(
+                    KademliaID::new(),
+                    &peer_addr,
+                    otherCommand::PING,
+                    None,
+                    None,
+                    None,
+                )
+                .await;
+        });
+        let (mut stream, _) = tokio::time::timeout(Duration::from_secs(2), peer.accept())
+            .await
+            .expect("The client prefers a stream")
+            .unwrap();
+        let len = stream.read_u32().await.unwrap() as usize;
+        let mut frame = vec![0u8; len];
+        stream.read_exact(&mut frame).await.unwrap();
+        let Ok(Packet::Plain(signed)) = bincode::deserialize::<Packet>(&frame) else {
+            panic!("Expected a plain signed message");
+        };
+        let (sender, _) = signed.open().unwrap();
+        assert!(sender.client && !sender.tcp);
+    }
+
+    #[tokio::test]
+    async fn test_client_uses_the_network_without_joining_it() {
+        let dir = std::env::temp_dir().join(format!("kad-client-{}", KademliaID::new().to_hex()));
+        let boot_node = spawn_configured_node(KademliaConfig {
+            advertise_address: free_local_addr(),
+            storage_dir: dir.clone(),
+            ..test_config()
+        })
+        .await;
+        let client = spawn_configured_node(KademliaConfig {
+            advertise_address: free_local_addr(),
+            boot_nodes: vec![boot_node.config.advertise_address.to_string()],
+            client: true,
+            ..test_config()
+        })
+        .await;
+        client.join().await.unwrap();
+        assert!(!client.networking.accepts_streams());
+
+        let value = "put by a client".to_string();
+        let key = KademliaID::from_data(&value);
+        let report = client.iterative_store(key, value.clone()).await.unwrap();
+        assert!(report.reached_quorum());
+        let report = client.iterative_find_value(key).await.unwrap();
+        assert_eq!(report.result, Some(value));
+
+        let (reply_tx, mut reply_rx) = mpsc::channel(1);
+        boot_node
+            .route_table_tx
+            .send(RouteTableCMD::GetAllContacts(reply_tx))
+            .await
+            .unwrap();
+        assert!(
+            reply_rx.recv().await.unwrap().is_empty(),
+            "Clients stay out of routing tables"
+        );
+
+        let response = boot_node
+            .networking
+            .send_rpc_request_await(
+                KademliaID::new(),
+                client.config.advertise_address,
+                otherCommand::PING,
+                None,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(response.is_none(), "Clients serve no requests");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
@@ -1,33 +1,64 @@
-use std::env;
-use std::process;
-pub fn check_bn() -> bool {
-    let bn_value = env::var("BN").unwrap_or_else(|_| "0".to_string());
-    bn_value == "1"
+use crate::constants::DEFAULT_PORT;
+use ed25519_dalek::SigningKey;
+use std::net::{IpAddr, Ipv4Addr};
+
+pub fn parse_boot_node_addresses(addresses: &str) -> Vec<String> {
+    addresses
+        .split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(|addr| {
+            // a bare IPv6 address is full of colons, it takes a port in brackets
+            if addr.parse::<IpAddr>().is_ok_and(|ip| ip.is_ipv6()) {
+                format!("[{}]:{}", addr, DEFAULT_PORT)
+            } else if addr.contains(':') {
+                addr.to_string()
+            } else {
+                format!("{}:{}", addr, DEFAULT_PORT)
+            }
+        })
+        .collect()
 }
 
-pub fn boot_node_address() -> String {
-    if !check_bn() {
-        match env::var("BNAD") {
-            Ok(boot_node_address) => boot_node_address,
-            Err(_) => "unset".to_string(),
+pub fn get_own_address() -> IpAddr {
+    let detected = std::process::Command::new("hostname")
+        .arg("-i")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|addrs| addrs.split_whitespace().find_map(|addr| addr.parse().ok()));
+
+    match detected {
+        Some(addr) => addr,
+        None => {
+            eprintln!("could not detect own address, falling back to 127.0.0.1");
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
         }
-    } else {
-        "BN is not set".to_string()
     }
 }
 
-pub fn get_own_address() -> String {
-    #[cfg(not(feature = "local"))]
-    {
-        "127.0.0.1".to_string()
-    }
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
 
-    #[cfg(feature = "local")]
-    {
-        let output = process::Command::new("hostname")
-            .arg("-i")
-            .output()
-            .expect("failed to execute hostname command");
-        String::from_utf8(output.stdout).unwrap().trim().to_string()
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
     }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub fn signing_key_from_hex(hex: &str) -> Option<SigningKey> {
+    let bytes: [u8; 32] = from_hex(hex)?.try_into().ok()?;
+    Some(SigningKey::from_bytes(&bytes))
 }